@@ -0,0 +1,71 @@
+//! Compares case-insensitive search against lowercasing every line first, as
+//! minigrep used to. Run with "cargo bench --bench case_insensitive".
+
+use std::hint::black_box;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use memchr::memmem;
+
+/// How many times each search is run, keeping the fastest
+const RUNS: usize = 10;
+
+/// The old approach, which allocates a lowercased copy of every line, then
+/// finds submatches in the copy with memmem like search_case_insensitive does
+fn lowercase_every_line<'a>(query: &str, contents: &'a str) -> Vec<(&'a str, Vec<Range<usize>>)> {
+    let query = query.to_lowercase();
+    let finder = memmem::Finder::new(&query);
+    contents
+        .lines()
+        .filter_map(|line| {
+            let submatches: Vec<_> = finder
+                .find_iter(line.to_lowercase().as_bytes())
+                .map(|start| start..start + query.len())
+                .collect();
+            (!submatches.is_empty()).then_some((line, submatches))
+        })
+        .collect()
+}
+
+fn fastest(mut search: impl FnMut() -> usize) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut found = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        found = black_box(search());
+        best = best.min(start.elapsed());
+    }
+    (best, found)
+}
+
+fn compare(name: &str, query: &str, contents: &str) {
+    let (before, expected) = fastest(|| lowercase_every_line(query, contents).len());
+    let (after, found) = fastest(|| minigrep::search_case_insensitive(query, contents).count());
+    assert_eq!(
+        expected, found,
+        "{name}: both searches should select the same lines"
+    );
+
+    println!(
+        "{name}, {} MiB: lowercasing every line {before:.2?}, \
+         search_case_insensitive {after:.2?} ({:.1}x)",
+        contents.len() / (1024 * 1024),
+        before.as_secs_f64() / after.as_secs_f64()
+    );
+}
+
+fn main() {
+    let poem = include_str!("../poem.txt");
+    let ascii = poem.repeat(200_000);
+    let mixed = format!("{poem}Grüße aus München, sagte die Straße.\n").repeat(200_000);
+    let log = "2024-05-01T12:00:00Z INFO request handled method=GET path=/api/v1/users \
+        status=200 duration_ms=12 user_agent=\"Mozilla/5.0 (X11; Linux x86_64)\"\n"
+        .repeat(300_000);
+
+    compare("ascii, common", "NOBODY", &ascii);
+    compare("ascii, rare", "FROG", &ascii);
+    compare("mixed, common", "NOBODY", &mixed);
+    compare("mixed, non-ascii", "MÜNCHEN", &mixed);
+    compare("long lines, common", "status=200", &log);
+    compare("long lines, rare", "STATUS=500", &log);
+}
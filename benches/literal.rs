@@ -0,0 +1,53 @@
+//! Compares literal search, which scans whole buffers for the query, against
+//! checking every line in turn. Run with "cargo bench --bench literal".
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// How many times each search is run, keeping the fastest
+const RUNS: usize = 10;
+
+/// The old approach, which splits every line before searching it
+fn contains_in_every_line<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    contents
+        .lines()
+        .filter(|line| line.contains(query))
+        .collect()
+}
+
+fn fastest(mut search: impl FnMut() -> usize) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut found = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        found = black_box(search());
+        best = best.min(start.elapsed());
+    }
+    (best, found)
+}
+
+fn compare(name: &str, query: &str, contents: &str) {
+    let (before, expected) = fastest(|| contains_in_every_line(query, contents).len());
+    let (after, found) = fastest(|| minigrep::search(query, contents).count());
+    assert_eq!(
+        expected, found,
+        "{name}: both searches should select the same lines"
+    );
+
+    println!(
+        "{name}, {} MiB: every line {before:.2?}, search {after:.2?} ({:.1}x)",
+        contents.len() / (1024 * 1024),
+        before.as_secs_f64() / after.as_secs_f64()
+    );
+}
+
+fn main() {
+    let poem = include_str!("../poem.txt");
+    let mut contents = poem.repeat(200_000);
+    // A line that only turns up once in a while
+    contents.insert_str(contents.len() / 2, "a needle in the middle\n");
+
+    compare("sparse", "needle", &contents);
+    compare("none", "toad", &contents);
+    compare("common", "nobody", &contents);
+}
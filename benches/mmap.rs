@@ -0,0 +1,70 @@
+//! Compares streaming files through a buffer against memory-mapping them, at a
+//! range of sizes, to find where mapping starts to pay off. Run with
+//! "cargo bench --bench mmap".
+
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use minigrep::Config;
+
+/// How many times each search is run, keeping the fastest
+const RUNS: usize = 10;
+
+fn fastest(mut search: impl FnMut() -> bool) -> (Duration, bool) {
+    let mut best = Duration::MAX;
+    let mut found = false;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        found = black_box(search());
+        best = best.min(start.elapsed());
+    }
+    (best, found)
+}
+
+/// Searches a file with -q, which prints nothing, so that only reading and
+/// searching it is timed. The queries never match, so every byte is read.
+fn search(mmap: &str, args: &[&str], path: &Path) -> bool {
+    let args = ["minigrep", "-q", mmap]
+        .into_iter()
+        .chain(args.iter().copied())
+        .chain([path.to_str().unwrap()])
+        .map(String::from);
+    Config::run(Config::build(args).unwrap()).unwrap()
+}
+
+fn compare(name: &str, args: &[&str], path: &Path) {
+    let size = fs::metadata(path).unwrap().len();
+    let (streamed, expected) = fastest(|| search("--no-mmap", args, path));
+    let (mapped, found) = fastest(|| search("--mmap", args, path));
+    assert_eq!(
+        expected, found,
+        "{name}: both searches should select the same lines"
+    );
+
+    println!(
+        "{name}, {} KiB: streamed {streamed:.2?}, mapped {mapped:.2?} ({:.2}x)",
+        size / 1024,
+        streamed.as_secs_f64() / mapped.as_secs_f64()
+    );
+}
+
+fn main() {
+    let poem = include_str!("../poem.txt");
+    let dir = env::temp_dir().join(format!("minigrep-mmap-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    for kib in [16, 64, 128, 256, 512, 1024, 2048, 4096, 16384, 65536] {
+        let path = dir.join(format!("{kib}.txt"));
+        let contents = poem.repeat(kib * 1024 / poem.len() + 1);
+        fs::write(&path, &contents[..kib * 1024]).unwrap();
+
+        // Literal queries scan whole buffers, so reading dominates
+        compare("literal", &["toad"], &path);
+        // Regular expressions search line by line, so reading matters less
+        compare("regex", &["-E", "to+ad"], &path);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while building or running a Config
+/// Argument: the commandline couldn't be understood
+/// Io: a file, directory or standard input couldn't be read
+/// Pattern: the query isn't a valid regular expression
+/// Encoding: the label given to --encoding isn't an encoding minigrep knows
+/// Patterns: there are too many patterns, or they're too long, to search for together
+/// Unsearched: this many paths couldn't be searched, though the rest were; each
+/// one was reported on stderr as it was skipped
+#[derive(Debug)]
+pub enum Error {
+    Argument(String),
+    Io { path: PathBuf, source: io::Error },
+    Pattern(regex::Error),
+    Encoding(String),
+    Patterns(aho_corasick::BuildError),
+    Unsearched(usize),
+}
+
+impl Error {
+    /// Wraps an io::Error with the path that was being read, for use with
+    /// map_err
+    ///
+    /// # Examples
+    ///
+    /// let contents = fs::read(path).map_err(Error::io(path))?;
+    pub(crate) fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Error {
+        let path = path.into();
+        move |source| Error::Io { path, source }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Argument(message) => write!(f, "{message}"),
+            Error::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::Pattern(source) => write!(f, "Invalid pattern: {source}"),
+            Error::Encoding(label) => write!(f, "Unknown encoding '{label}'"),
+            Error::Patterns(source) => write!(f, "Invalid patterns: {source}"),
+            Error::Unsearched(1) => write!(f, "1 path couldn't be searched"),
+            Error::Unsearched(count) => write!(f, "{count} paths couldn't be searched"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Argument(_) | Error::Encoding(_) | Error::Unsearched(_) => None,
+            Error::Io { source, .. } => Some(source),
+            Error::Pattern(source) => Some(source),
+            Error::Patterns(source) => Some(source),
+        }
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(source: regex::Error) -> Self {
+        Error::Pattern(source)
+    }
+}
+
+impl From<aho_corasick::BuildError> for Error {
+    fn from(source: aho_corasick::BuildError) -> Self {
+        Error::Patterns(source)
+    }
+}
@@ -1,59 +1,827 @@
 use std::env;
-use std::error::Error;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Write};
+use std::iter;
+use std::ops::{ControlFlow, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use encoding_rs::Encoding;
+use regex::bytes::Regex;
+
+pub use error::Error;
+use matcher::Matcher;
+use printer::Printer;
+use searcher::{Input, Line, Lines};
+
+mod error;
+mod matcher;
+mod printer;
+mod searcher;
+
+/// The path that stands for standard input, as with most Unix tools
+const STDIN_PATH: &str = "-";
+
+/// How standard input is named in output and errors
+const STDIN_NAME: &str = "(standard input)";
+
+/// How standard output is named in errors writing to it
+const STDOUT_NAME: &str = "(standard output)";
+
+/// Printed by --help
+const USAGE: &str = "\
+Usage: minigrep [OPTION]... QUERY [PATH]...
+  or:  minigrep [OPTION]... -e PATTERN... [PATH]...
+Search for QUERY in each PATH. Directories are searched recursively, and
+standard input is searched when no PATH is given or PATH is \"-\".
+
+Matching:
+  -e, --regexp=PATTERN       search for PATTERN; may be repeated to select lines
+                             matching any of them, and then there's no QUERY
+  -f, --file=FILE            search for every line of FILE as a PATTERN
+  -E, --regex                treat QUERY as a regular expression
+  -i, --ignore-case          ignore case distinctions, also set by $IGNORE_CASE
+      --no-ignore-case       don't ignore case distinctions, even if $IGNORE_CASE
+                             is set
+  -S, --smart-case           ignore case distinctions unless QUERY has an
+                             uppercase letter
+      --case-folding=MODE    how -i compares case; MODE is full, the default, so
+                             that e.g. \"ß\" matches \"SS\", or simple, mapping
+                             one character to one; regular expressions always
+                             use simple folding
+  -v, --invert-match         select lines that don't match
+  -w, --word-regexp          only match whole words, with no letter, digit or _
+                             just before or after
+  -x, --line-regexp          only match whole lines
+
+Output:
+  -n, --line-number          print the line number of each line
+  -b, --byte-offset          print the byte offset of each line
+      --column               print the column of the first match in each line
+      --color[=WHEN]         highlight matches; WHEN is auto, always or never
+  -o, --only-matching        print only the matched parts of each line, one per
+                             line, ignoring any context
+  -r, --replace=TEXT         print TEXT in place of each match; with -E, $1 or
+                             ${NAME} in TEXT stands for what a group captured,
+                             and $$ for \"$\"
+      --json                 print results as JSON Lines, one object per file
+                             begun, selected line, context line and file ended,
+                             then a summary; text that isn't UTF-8 is base64
+  -c, --count                print only the number of selected lines per file
+  -l, --files-with-matches   print only the names of files with selected lines
+  -L, --files-without-match  print only the names of files without selected lines
+  -q, --quiet, --silent      print nothing and stop at the first selected line
+
+Binary files:
+      --binary-files=TYPE    how to treat files with NUL bytes; TYPE is binary,
+                             to print \"Binary file X matches\" instead of lines,
+                             text, to search them like any other file, or
+                             without-match, to skip them
+      --binary               the same as --binary-files=binary, the default
+  -a, --text                 the same as --binary-files=text
+  -I                         the same as --binary-files=without-match
+
+Context:
+  -A, --after-context=NUM    print NUM lines after each selected line
+  -B, --before-context=NUM   print NUM lines before each selected line
+  -C, --context=NUM          print NUM lines before and after each selected line
+
+Input:
+      --encoding=ENC         decode input from ENC, such as latin1, windows-1252 or
+                             utf-16le, before searching; auto, the default, means
+                             UTF-8 unless the input starts with a byte order mark
+      --mmap                 memory-map files when possible
+      --no-mmap              never memory-map files; by default only big files are
+
+  -h, --help                 print this help and exit
+  -V, --version              print the version and exit
+
+Options may appear anywhere, short options may be combined as in -in, and
+everything after -- is treated as QUERY or PATH.
+
+The exit status is 0 if a line was selected, 1 if none were and 2 on error.
+";
+
+/// Whether a commandline option takes a value
+#[derive(Clone, Copy, PartialEq)]
+enum Value {
+    None,
+    Required,
+    Optional,
+}
+
+/// Every option minigrep understands, as its short name, long name and
+/// whether it takes a value. Optional values can only be given with "=".
+const OPTIONS: &[(Option<char>, &str, Value)] = &[
+    (Some('e'), "regexp", Value::Required),
+    (Some('f'), "file", Value::Required),
+    (Some('E'), "regex", Value::None),
+    (Some('i'), "ignore-case", Value::None),
+    (None, "no-ignore-case", Value::None),
+    (Some('S'), "smart-case", Value::None),
+    (None, "case-folding", Value::Required),
+    (Some('v'), "invert-match", Value::None),
+    (Some('w'), "word-regexp", Value::None),
+    (Some('x'), "line-regexp", Value::None),
+    (Some('n'), "line-number", Value::None),
+    (Some('b'), "byte-offset", Value::None),
+    (None, "column", Value::None),
+    (None, "color", Value::Optional),
+    (Some('o'), "only-matching", Value::None),
+    (Some('r'), "replace", Value::Required),
+    (None, "json", Value::None),
+    (Some('c'), "count", Value::None),
+    (Some('l'), "files-with-matches", Value::None),
+    (Some('L'), "files-without-match", Value::None),
+    (Some('q'), "quiet", Value::None),
+    (None, "silent", Value::None),
+    (None, "binary-files", Value::Required),
+    (None, "binary", Value::None),
+    (Some('a'), "text", Value::None),
+    (Some('I'), "without-match", Value::None),
+    (Some('A'), "after-context", Value::Required),
+    (Some('B'), "before-context", Value::Required),
+    (Some('C'), "context", Value::Required),
+    (None, "encoding", Value::Required),
+    (None, "mmap", Value::None),
+    (None, "no-mmap", Value::None),
+    (Some('h'), "help", Value::None),
+    (Some('V'), "version", Value::None),
+];
+
+/// How to treat files that look binary because they contain a NUL byte
+/// Binary: print "Binary file X matches" in place of selected lines
+/// Text: search them like any other file
+/// WithoutMatch: skip them, as if nothing in them matched
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryFiles {
+    #[default]
+    Binary,
+    Text,
+    WithoutMatch,
+}
+
+/// How case is folded when ignoring case, as defined by Unicode
+/// Full: characters may fold to several, so "ß" and "SS" are the same
+/// Simple: each character folds to exactly one, so "ß" and "SS" differ
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CaseFolding {
+    #[default]
+    Full,
+    Simple,
+}
 
 /// A struct encapsulating commandline arguments for minigrep
-/// query: a word to search for
-/// file_path: a file path
-/// ignore_case: true if --ignore_case is passed, or if $IGNORE_CASE is set
+/// patterns: what to search for, selecting lines that match any of them; given by
+/// -e and -f, or otherwise by the first argument that isn't an option
+/// file_paths: files or directories to search, where "-" means standard input
+/// ignore_case: true if case should be ignored; the last of -i, --no-ignore-case and
+/// -S wins, where -S ignores case unless the query has an uppercase letter, and
+/// $IGNORE_CASE is only consulted if none of them is passed
+/// case_folding: how case is folded when ignoring case, set by --case-folding
+/// regex: true if --regex or -E is passed, treating the query as a regular expression
+/// line_number: true if -n is passed, printing the line number of each match
+/// byte_offset: true if -b is passed, printing the byte offset of each matching line
+/// column: true if --column is passed, printing the column of the first match
+/// color: true if matches should be highlighted, decided by --color=auto|always|never,
+/// where auto colors only when stdout is a terminal and $NO_COLOR isn't set
+/// invert_match: true if -v or --invert-match is passed, selecting lines that don't match
+/// word_regexp: true if -w or --word-regexp is passed, only matching whole words
+/// line_regexp: true if -x or --line-regexp is passed, only matching whole lines
+/// only_matching: true if -o or --only-matching is passed, printing each match on
+/// its own line instead of whole lines
+/// replace: the text set by -r or --replace to print in place of each match, where
+/// $1 or ${name} in a regular expression stands for what that group captured
+/// json: true if --json is passed, printing results as JSON Lines events instead
+/// of lines of text
+/// count: true if -c or --count is passed, printing how many lines were selected per file
+/// files_with_matches: true if -l or --files-with-matches is passed, printing only the
+/// names of files with a selected line
+/// files_without_match: true if -L or --files-without-match is passed, printing only the
+/// names of files without a selected line
+/// quiet: true if -q, --quiet or --silent is passed, printing nothing and stopping at
+/// the first selected line
+/// before_context: number of lines to print before each match, set by -B or -C
+/// after_context: number of lines to print after each match, set by -A or -C
+/// binary_files: how to treat binary files, set by --binary-files, --binary, -a or -I
+/// encoding: the encoding set by --encoding, or None to assume UTF-8; a byte order
+/// mark at the start of an input overrides either
+/// mmap: Some(true) if --mmap is passed and Some(false) if --no-mmap is passed, forcing
+/// whether files are memory-mapped, or None to decide by the size of each file
+/// help: true if -h or --help is passed, printing usage instead of searching
+/// version: true if -V or --version is passed, printing the version instead of searching
+#[derive(Default)]
 pub struct Config {
-    pub query: String,
-    pub file_path: String,
+    pub patterns: Vec<String>,
+    pub file_paths: Vec<String>,
     pub ignore_case: bool,
+    pub case_folding: CaseFolding,
+    pub regex: bool,
+    pub line_number: bool,
+    pub byte_offset: bool,
+    pub column: bool,
+    pub color: bool,
+    pub invert_match: bool,
+    pub word_regexp: bool,
+    pub line_regexp: bool,
+    pub only_matching: bool,
+    pub replace: Option<String>,
+    pub json: bool,
+    pub count: bool,
+    pub files_with_matches: bool,
+    pub files_without_match: bool,
+    pub quiet: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub binary_files: BinaryFiles,
+    pub encoding: Option<&'static Encoding>,
+    pub mmap: Option<bool>,
+    pub help: bool,
+    pub version: bool,
 }
 
 /// Parses commandline arguments from std::env
 impl Config {
     
-    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
+    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, Error> {
         args.next();
 
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
+        let mut options = Vec::new();
+        let mut positionals = Vec::new();
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positionals.extend(args.by_ref());
+            } else if let Some(option) = arg.strip_prefix("--") {
+                let (name, value) = match option.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (option, None),
+                };
+                let &(_, name, kind) = OPTIONS
+                    .iter()
+                    .find(|(_, long, _)| *long == name)
+                    .ok_or_else(|| Error::Argument(format!("Unknown option '--{name}'")))?;
+
+                let value = match (kind, value) {
+                    (Value::None, Some(_)) => {
+                        return Err(Error::Argument(format!(
+                            "Option '--{name}' doesn't take a value"
+                        )))
+                    }
+                    (Value::Required, None) => match args.next() {
+                        Some(value) => Some(value),
+                        None => {
+                            return Err(Error::Argument(format!("Option '--{name}' needs a value")))
+                        }
+                    },
+                    (_, value) => value,
+                };
+                options.push((name, value));
+            } else if arg.starts_with('-') && arg != STDIN_PATH {
+                // Short options can be combined, as in -in. One that takes a
+                // value uses the rest of the argument or the next one, so
+                // both -A3 and -A 3 work.
+                for (index, short) in arg.char_indices().skip(1) {
+                    let &(_, name, kind) = OPTIONS
+                        .iter()
+                        .find(|(option, _, _)| *option == Some(short))
+                        .ok_or_else(|| Error::Argument(format!("Unknown option '-{short}'")))?;
+
+                    if kind != Value::Required {
+                        options.push((name, None));
+                        continue;
+                    }
+                    let rest = &arg[index + short.len_utf8()..];
+                    let value = match rest {
+                        "" => args.next().ok_or_else(|| {
+                            Error::Argument(format!("Option '-{short}' needs a value"))
+                        })?,
+                        rest => rest.to_string(),
+                    };
+                    options.push((name, Some(value)));
+                    break;
+                }
+            } else {
+                positionals.push(arg);
+            }
+        }
+
+        let mut config = Config::default();
+        let mut patterns = None;
+        let (mut ignore_case, mut smart_case) = (None, false);
+        let mut color_choice = String::from("auto");
+        let (mut context, mut before_context, mut after_context) = (None, None, None);
+
+        for (name, value) in options {
+            let lines = || match value.as_deref().map(str::parse) {
+                Some(Ok(lines)) => Ok(Some(lines)),
+                _ => Err(Error::Argument(format!(
+                    "Expected a number of lines for '--{name}'"
+                ))),
+            };
+            match name {
+                "regexp" => patterns
+                    .get_or_insert_with(Vec::new)
+                    .push(value.unwrap_or_default()),
+                "file" => {
+                    let path = value.unwrap_or_default();
+                    let contents = fs::read_to_string(&path).map_err(Error::io(&path))?;
+                    patterns
+                        .get_or_insert_with(Vec::new)
+                        .extend(contents.lines().map(String::from));
+                }
+                "regex" => config.regex = true,
+                "ignore-case" => (ignore_case, smart_case) = (Some(true), false),
+                "no-ignore-case" => (ignore_case, smart_case) = (Some(false), false),
+                "smart-case" => (ignore_case, smart_case) = (None, true),
+                "case-folding" => {
+                    config.case_folding = match value.as_deref() {
+                        Some("full") => CaseFolding::Full,
+                        Some("simple") => CaseFolding::Simple,
+                        _ => {
+                            return Err(Error::Argument(String::from(
+                                "Expected --case-folding to be full or simple",
+                            )))
+                        }
+                    }
+                }
+                "invert-match" => config.invert_match = true,
+                "word-regexp" => config.word_regexp = true,
+                "line-regexp" => config.line_regexp = true,
+                "line-number" => config.line_number = true,
+                "byte-offset" => config.byte_offset = true,
+                "column" => config.column = true,
+                "color" => color_choice = value.unwrap_or_else(|| String::from("auto")),
+                "only-matching" => config.only_matching = true,
+                "replace" => config.replace = value,
+                "json" => config.json = true,
+                "count" => config.count = true,
+                "files-with-matches" => config.files_with_matches = true,
+                "files-without-match" => config.files_without_match = true,
+                "quiet" | "silent" => config.quiet = true,
+                "after-context" => after_context = lines()?,
+                "before-context" => before_context = lines()?,
+                "context" => context = lines()?,
+                "binary-files" => {
+                    config.binary_files = match value.as_deref() {
+                        Some("binary") => BinaryFiles::Binary,
+                        Some("text") => BinaryFiles::Text,
+                        Some("without-match") => BinaryFiles::WithoutMatch,
+                        _ => {
+                            return Err(Error::Argument(String::from(
+                                "Expected --binary-files to be binary, text or without-match",
+                            )))
+                        }
+                    }
+                }
+                "binary" => config.binary_files = BinaryFiles::Binary,
+                "text" => config.binary_files = BinaryFiles::Text,
+                "without-match" => config.binary_files = BinaryFiles::WithoutMatch,
+                "encoding" => {
+                    let label = value.unwrap_or_default();
+                    config.encoding = match label.as_str() {
+                        "auto" => None,
+                        _ => match Encoding::for_label(label.as_bytes()) {
+                            Some(encoding) => Some(encoding),
+                            None => return Err(Error::Encoding(label)),
+                        },
+                    }
+                }
+                "mmap" => config.mmap = Some(true),
+                "no-mmap" => config.mmap = Some(false),
+                "help" => config.help = true,
+                "version" => config.version = true,
+                _ => unreachable!("every option in OPTIONS is handled"),
+            }
+        }
+
+        config.color = match color_choice.as_str() {
+            "always" => true,
+            "never" => false,
+            "auto" => io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            _ => {
+                return Err(Error::Argument(String::from(
+                    "Expected --color to be auto, always or never",
+                )))
+            }
         };
 
-        let file_path = match args.next() {
-            Some(file_path) => file_path,
-            None => return Err("Didn't get a filepath"),
+        // As in grep, -A and -B take precedence over -C whatever their order
+        config.before_context = before_context.or(context).unwrap_or(0);
+        config.after_context = after_context.or(context).unwrap_or(0);
+
+        if config.help || config.version {
+            return Ok(config);
+        }
+
+        // Every event already carries the counts and submatches these would print
+        if config.json
+            && (config.only_matching
+                || config.count
+                || config.files_with_matches
+                || config.files_without_match)
+        {
+            return Err(Error::Argument(String::from(
+                "--json can't be used with -o, -c, -l or -L",
+            )));
+        }
+
+        // As in grep, the query is only taken from the arguments without -e or -f
+        let mut positionals = positionals.into_iter();
+        config.patterns = match patterns {
+            Some(patterns) => patterns,
+            None => match positionals.next() {
+                Some(arg) => vec![arg],
+                None => return Err(Error::Argument(String::from("Didn't get a query string"))),
+            },
         };
 
-        let ignore_case = if args.any(|arg| arg == "--ignore-case") {
-            true
-        } else {
-            env::var("IGNORE_CASE").is_ok()
+        config.file_paths = positionals.collect();
+        if config.file_paths.is_empty() {
+            config.file_paths.push(STDIN_PATH.to_string());
+        }
+
+        // Flags win over $IGNORE_CASE, since they were passed for this search
+        config.ignore_case = match ignore_case {
+            Some(ignore_case) => ignore_case,
+            None if smart_case => !config
+                .patterns
+                .iter()
+                .any(|pattern| has_uppercase(pattern, config.regex)),
+            None => env::var("IGNORE_CASE").is_ok(),
         };
 
-        Ok(Config {
-            query,
-            file_path,
-            ignore_case,
-        })
+        Ok(config)
     }
 
-    pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-        let contents = fs::read_to_string(config.file_path)?;
+    /// Searches every path in the configuration, printing results as it goes
+    /// 
+    /// Returns true if any line was selected, which main turns into grep's
+    /// exit codes: 0 if a line was selected, 1 if none were and 2 on error.
+    pub fn run(config: Config) -> Result<bool, Error> {
+        let mut stdout = io::stdout().lock();
+        if config.help || config.version {
+            let written = if config.help {
+                stdout.write_all(USAGE.as_bytes())
+            } else {
+                writeln!(stdout, "minigrep {}", env!("CARGO_PKG_VERSION"))
+            };
+            stopped_early(written)?;
+            return Ok(true);
+        }
+
+        let matcher = Matcher::new(&config)?;
+
+        // Like grep, a path that can't be searched is reported and skipped,
+        // and only makes the search fail once every other path is done
+        let mut errors = Vec::new();
+        let mut files = Vec::new();
+        let mut recursive = false;
+        for path in &config.file_paths {
+            let path = Path::new(path);
+            recursive |= path.is_dir();
+            collect_files(path, &mut files, &mut errors);
+        }
+        for err in &errors {
+            eprintln!("minigrep: {err}");
+        }
+        let mut unsearched = errors.len();
+
+        // Like grep, prefix lines with their file once more than one file may match
+        let with_filename = recursive || config.file_paths.len() > 1;
+        let mut printer = Printer::new(&config, with_filename, stdout);
+        let mut any_selected = false;
+
+        let written = config.search_files(
+            &files,
+            &matcher,
+            &mut printer,
+            &mut any_selected,
+            &mut unsearched,
+        );
+        if stopped_early(written)? {
+            return Ok(true);
+        }
+
+        // As in grep, -q succeeds once a line is selected, whatever else failed
+        if unsearched > 0 && !(config.quiet && any_selected) {
+            return Err(Error::Unsearched(unsearched));
+        }
+        Ok(any_selected)
+    }
+
+    /// Searches each file in turn, printing results as it goes
+    /// 
+    /// A file that can't be searched is reported on stderr and counted in
+    /// "unsearched", and the search carries on. Only a failure to write the
+    /// results stops it, and is returned.
+    /// 
+    /// # Arguments
+    /// 
+    /// * "files" - the files to search, where "-" means standard input
+    /// * "matcher" - the compiled patterns
+    /// * "printer" - where results are printed
+    /// * "any_selected" - set to true once a line is selected
+    /// * "unsearched" - incremented for each file that can't be searched
+    fn search_files(
+        &self,
+        files: &[PathBuf],
+        matcher: &Matcher,
+        printer: &mut Printer<impl Write>,
+        any_selected: &mut bool,
+        unsearched: &mut usize,
+    ) -> io::Result<()> {
+        for file in files {
+            let selected = match self.search_path(file, matcher, printer)? {
+                Ok(selected) => selected,
+                Err(err) => {
+                    eprintln!("minigrep: {err}");
+                    *unsearched += 1;
+                    continue;
+                }
+            };
+
+            *any_selected |= selected > 0;
+
+            // -q wins over everything, then -l and -L win over -c, as in grep
+            if self.quiet {
+                if *any_selected {
+                    break;
+                }
+            } else if self.files_with_matches || self.files_without_match {
+                if (selected > 0) == self.files_with_matches {
+                    printer.path()?;
+                }
+            } else if self.count {
+                printer.count(selected)?;
+            }
+        }
+        printer.summary()
+    }
+
+    /// Opens a file, or standard input for "-", and searches it, returning
+    /// how many lines were selected
+    /// 
+    /// Big files are memory-mapped as --mmap and --no-mmap allow, and
+    /// everything else is streamed. Errors reading the file are kept apart
+    /// from errors writing the results, which come first.
+    fn search_path(
+        &self,
+        file: &Path,
+        matcher: &Matcher,
+        printer: &mut Printer<impl Write>,
+    ) -> io::Result<Result<usize, Error>> {
+        if file.as_os_str() == STDIN_PATH {
+            let stdin = Input::Stream(Box::new(io::stdin().lock()));
+            return self.search_input(stdin, Path::new(STDIN_NAME), matcher, printer);
+        }
 
-        let results = if config.ignore_case {
-            search_case_insensitive(&config.query, &contents)
+        let opened = File::open(file)
+            .map_err(Error::io(file))
+            .and_then(|handle| {
+                let map = if searcher::should_mmap(&handle, self.mmap) {
+                    Some(searcher::mmap(&handle, file)?)
+                } else {
+                    None
+                };
+                Ok((handle, map))
+            });
+        let (handle, map) = match opened {
+            Ok(opened) => opened,
+            Err(err) => return Ok(Err(err)),
+        };
+        let input = match &map {
+            Some(map) => Input::Bytes(map),
+            None => Input::Stream(Box::new(handle)),
+        };
+        self.search_input(input, file, matcher, printer)
+    }
+
+    /// True if nothing but whether a file has a selected line matters, so
+    /// searching it can stop as soon as the first one is found
+    fn stops_at_first_match(&self) -> bool {
+        self.files_with_matches || self.files_without_match || self.quiet
+    }
+
+    /// Streams lines from an input through the matcher, printing lines as
+    /// it goes unless only a summary was asked for, and returns how many
+    /// lines were selected
+    /// 
+    /// Reading stops at the first selected line if that's all that matters.
+    /// Standard input is searched the same way, so results appear as soon as
+    /// they're piped in rather than when the writer closes the pipe. Binary
+    /// input is handled as --binary-files says.
+    fn search_input(
+        &self,
+        input: Input,
+        path: &Path,
+        matcher: &Matcher,
+        printer: &mut Printer<impl Write>,
+    ) -> io::Result<Result<usize, Error>> {
+        printer.begin(path)?;
+        let mut selected = 0;
+        let mut binary_matched = false;
+        let mut written = Ok(());
+        let prints_lines = !self.stops_at_first_match() && !self.count;
+        // Skipping lines is only safe when the ones that don't match are unwanted
+        let prefilter = if self.invert_match || printer.wants_context() {
+            None
         } else {
-            search(&config.query, &contents)
+            matcher.prefilter()
         };
 
-        for line in results {
-            println!("{line}");
+        let read = searcher::for_each_line(
+            input,
+            path,
+            self.encoding,
+            prefilter.as_ref(),
+            |input_line: Line| {
+                let Line {
+                    number,
+                    offset,
+                    bytes,
+                    binary,
+                    ..
+                } = input_line;
+                let binary = binary && self.binary_files != BinaryFiles::Text;
+                if binary && self.binary_files == BinaryFiles::WithoutMatch {
+                    return ControlFlow::Break(());
+                }
+
+                let line = trim_line_terminator(bytes);
+                let submatches = matcher.find_all(line);
+
+                let printed = if submatches.is_empty() == self.invert_match {
+                    selected += 1;
+                    if self.stops_at_first_match() {
+                        return ControlFlow::Break(());
+                    }
+                    if prints_lines && binary {
+                        binary_matched = true;
+                        return ControlFlow::Break(());
+                    }
+                    if !prints_lines {
+                        return ControlFlow::Continue(());
+                    }
+                    // With -r the replaced line is printed, highlighting the
+                    // replacements, but positions are still those of the match
+                    let mut replaced = Vec::new();
+                    let replacements = self.replace.as_ref().map(|replacement| {
+                        matcher.replace(line, &submatches, replacement.as_bytes(), &mut replaced)
+                    });
+                    let result = Match {
+                        line_number: number,
+                        line_range: offset..offset + line.len(),
+                        submatches,
+                        line,
+                        source: None,
+                    };
+                    printer.matched(
+                        &result,
+                        replacements
+                            .as_deref()
+                            .map(|ranges| (replaced.as_slice(), ranges)),
+                        |at| input_line.input_offset(at),
+                    )
+                } else if prints_lines && !binary {
+                    printer.context(number, offset, line)
+                } else {
+                    Ok(())
+                };
+                match printed {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(err) => {
+                        written = Err(err);
+                        ControlFlow::Break(())
+                    }
+                }
+            },
+        );
+        written?;
+
+        if let Err(err) = read {
+            // Whatever was printed before the error still gets its end
+            printer.end(selected)?;
+            return Ok(Err(err));
+        }
+        if binary_matched {
+            printer.binary_matches()?;
         }
-        Ok(())
+        printer.end(selected)?;
+        Ok(Ok(selected))
+    }
+}
+
+/// Whether writing output stopped early because its reader went away, as
+/// when piping into head
+/// 
+/// Like ripgrep, that counts as success rather than an error, since everything
+/// that was wanted has been printed. Any other failure to write is an error.
+fn stopped_early(written: io::Result<()>) -> Result<bool, Error> {
+    match written {
+        Ok(()) => Ok(false),
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(true),
+        Err(err) => Err(Error::io(STDOUT_NAME)(err)),
+    }
+}
+
+/// Whether a query has an uppercase letter, for -S
+/// 
+/// In a regular expression the character after a backslash is skipped, so
+/// escapes like \S or \W don't count as uppercase.
+fn has_uppercase(query: &str, regex: bool) -> bool {
+    let mut characters = query.chars();
+    while let Some(character) = characters.next() {
+        if regex && character == '\\' {
+            characters.next();
+        } else if character.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Expands a path into the regular files it refers to
+/// 
+/// Directories are walked recursively in sorted order. Symbolic links found
+/// while walking are skipped so that link cycles can't recurse forever, but a
+/// path given directly is always followed. A directory or entry that can't be
+/// read is left out, and the walk carries on with the rest.
+/// 
+/// # Arguments
+/// 
+/// * "path" - a file or directory path
+/// * "files" - the list that found files are appended to
+/// * "errors" - the list that errors reading directories are appended to
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>, errors: &mut Vec<Error>) {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return;
+    }
+
+    let mut entries = Vec::new();
+    match fs::read_dir(path) {
+        Ok(read_dir) => {
+            for entry in read_dir {
+                match entry {
+                    Ok(entry) => entries.push(entry),
+                    Err(source) => errors.push(Error::io(path)(source)),
+                }
+            }
+        }
+        Err(source) => errors.push(Error::io(path)(source)),
+    }
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => collect_files(&entry.path(), files, errors),
+            Ok(file_type) if file_type.is_file() => files.push(entry.path()),
+            Ok(_) => {}
+            Err(source) => errors.push(Error::io(entry.path())(source)),
+        }
+    }
+}
+
+/// A line that matched a search
+/// line_number: 1-based number of the line within the searched contents
+/// line_range: byte range of the line within the contents, without its line terminator
+/// submatches: byte ranges of every match within the line, relative to the start of the line
+/// line: the bytes of the line, without its line terminator, which needn't be UTF-8
+/// source: where the contents came from, such as a file path, if known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match<'a> {
+    pub line_number: usize,
+    pub line_range: Range<usize>,
+    pub submatches: Vec<Range<usize>>,
+    pub line: &'a [u8],
+    pub source: Option<Arc<str>>,
+}
+
+impl Match<'_> {
+    /// Tags the match with the source its contents came from
+    /// 
+    /// # Examples
+    /// 
+    /// let source: Arc<str> = Arc::from("poem.txt");
+    /// let results = search("frog", contents).map(|result| result.with_source(&source));
+    pub fn with_source(self, source: &Arc<str>) -> Self {
+        Match {
+            source: Some(Arc::clone(source)),
+            ..self
+        }
+    }
+
+    /// 0-based byte offset of the start of the line within the contents
+    pub fn byte_offset(&self) -> usize {
+        self.line_range.start
+    }
+
+    /// 1-based byte column of the first match within the line
+    pub fn column(&self) -> usize {
+        self.submatches
+            .first()
+            .map_or(1, |submatch| submatch.start + 1)
     }
 }
 
@@ -62,45 +830,184 @@ impl Config {
 /// # Arguments
 /// 
 /// * "query" - string slice encapsulating the query text
-/// * "contents" - string slice representing document contents
+/// * "contents" - string or byte slice representing document contents
 /// 
 /// # Examples
 /// 
 /// let query: &str = "brown";
 /// let contents: &str = "the quick brown fox";
 /// 
-/// let results: Vec<&str> = search(query, contents);
-pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    contents
-        .lines()
-        .filter(|line| line.contains(query))
-        .collect()
+/// let results: Vec<Match> = search(query, contents).collect();
+pub fn search<'a>(
+    query: &str,
+    contents: &'a (impl AsRef<[u8]> + ?Sized),
+) -> impl Iterator<Item = Match<'a>> + 'a {
+    search_lines(contents.as_ref(), Matcher::literal(query))
 }
 
-/// Searches case-insensitively
+/// Searches case-insensitively, using full Unicode case folding
+/// 
+/// Submatches are byte ranges of the original contents, even where folding
+/// changes the length of the text, as with "ß" and "ss".
 /// 
 /// # Arguments
 /// 
 /// * "query" - string slice encapsulating the query text
-/// * "contents" - string slice representing document contents
+/// * "contents" - string or byte slice representing document contents
 /// 
 /// # Examples
 /// 
 /// let query: &str = "BROWN";
 /// let contents: &str = "the quick bRoWn fox";
 /// 
-/// let results: Vec<&str> = search(query, contents);
-pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    let query = query.to_lowercase();
-    contents
-        .lines()
-        .filter(|line| line.to_lowercase().contains(&query))
-        .collect()
+/// let results: Vec<Match> = search_case_insensitive(query, contents).collect();
+pub fn search_case_insensitive<'a>(
+    query: &str,
+    contents: &'a (impl AsRef<[u8]> + ?Sized),
+) -> impl Iterator<Item = Match<'a>> + 'a {
+    search_lines(contents.as_ref(), Matcher::case_insensitive(query))
+}
+
+/// Searches for lines matching a compiled regular expression
+/// 
+/// The pattern works on bytes so that contents needn't be UTF-8. Case
+/// sensitivity is decided when the pattern is built, e.g. with
+/// regex::bytes::RegexBuilder::case_insensitive
+/// 
+/// # Arguments
+/// 
+/// * "pattern" - compiled regular expression to match each line against
+/// * "contents" - string or byte slice representing document contents
+/// 
+/// # Examples
+/// 
+/// let pattern: Regex = regex::bytes::Regex::new(r"^fn \w+\(").unwrap();
+/// let contents: &str = "fn main() {\n    run();\n}";
+/// 
+/// let results: Vec<Match> = search_regex(&pattern, contents).collect();
+pub fn search_regex<'a>(
+    pattern: &Regex,
+    contents: &'a (impl AsRef<[u8]> + ?Sized),
+) -> impl Iterator<Item = Match<'a>> + 'a {
+    search_lines(contents.as_ref(), Matcher::regex(pattern.clone()))
+}
+
+/// Turns the results of any search into the lines of "contents" that it
+/// didn't match, each with no submatches
+/// 
+/// # Arguments
+/// 
+/// * "contents" - string or byte slice representing the document that was searched
+/// * "results" - matches from searching "contents", in line order
+/// 
+/// # Examples
+/// 
+/// let query: &str = "frog";
+/// let contents: &str = "How public, like a frog\nTo tell your name the livelong day";
+/// 
+/// let results: Vec<Match> = invert(contents, search(query, contents)).collect();
+pub fn invert<'a>(
+    contents: &'a (impl AsRef<[u8]> + ?Sized),
+    results: impl Iterator<Item = Match<'a>> + 'a,
+) -> impl Iterator<Item = Match<'a>> + 'a {
+    let mut results = results.peekable();
+
+    let mut lines = Lines::new(contents.as_ref(), 0);
+
+    iter::from_fn(move || loop {
+        let (line_number, line_range, line) = numbered(lines.next_line(None)?);
+        if results
+            .next_if(|result| result.line_number == line_number)
+            .is_none()
+        {
+            return Some(Match {
+                line_number,
+                line_range,
+                submatches: Vec::new(),
+                line,
+                source: None,
+            });
+        }
+    })
+}
+
+/// Runs a matcher over every line of "contents", yielding a Match for each
+/// line where it finds at least one submatch
+/// 
+/// Literal queries scan the whole of "contents" for the next occurrence and
+/// only then look for the line around it, rather than splitting every line.
+fn search_lines(contents: &[u8], matcher: Matcher) -> impl Iterator<Item = Match<'_>> {
+    let mut lines = Lines::new(contents, 0);
+
+    iter::from_fn(move || loop {
+        let (line_number, line_range, line) =
+            numbered(lines.next_line(matcher.prefilter().as_ref())?);
+        let submatches = matcher.find_all(line);
+        if !submatches.is_empty() {
+            return Some(Match {
+                line_number,
+                line_range,
+                submatches,
+                line,
+                source: None,
+            });
+        }
+    })
+}
+
+/// Splits a line from the searcher into its 1-based number, its byte range
+/// within the input and the line itself, without its terminator
+/// 
+/// Lines are split the same way as str::lines, on "\n" with an optional
+/// preceding "\r".
+fn numbered(line: Line<'_>) -> (usize, Range<usize>, &[u8]) {
+    let bytes = trim_line_terminator(line.bytes);
+    (line.number, line.offset..line.offset + bytes.len(), bytes)
+}
+
+/// Strips a trailing "\n" or "\r\n" from a line
+fn trim_line_terminator(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::slice;
+
+    fn lines<'a>(results: impl Iterator<Item = Match<'a>>) -> Vec<&'a str> {
+        results
+            .map(|result| std::str::from_utf8(result.line).unwrap())
+            .collect()
+    }
+
+    fn config(args: &[&str]) -> Config {
+        let args = iter::once("minigrep").chain(args.iter().copied());
+        Config::build(args.map(String::from)).unwrap()
+    }
+
+    /// Searches files the way run does, with the options in "args", and
+    /// returns what was printed, whether any line was selected and how many
+    /// files couldn't be searched
+    fn run_on(args: &[&str], files: &[PathBuf]) -> (String, bool, usize) {
+        let config = config(args);
+        let matcher = Matcher::new(&config).unwrap();
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&config, files.len() > 1, &mut out);
+        let (mut any_selected, mut unsearched) = (false, 0);
+        config
+            .search_files(
+                files,
+                &matcher,
+                &mut printer,
+                &mut any_selected,
+                &mut unsearched,
+            )
+            .unwrap();
+        drop(printer);
+        (String::from_utf8(out).unwrap(), any_selected, unsearched)
+    }
 
     #[test]
     fn one_result() {
@@ -110,7 +1017,10 @@ Rust:
 safe, fast, productive.
 Pick three.";
 
-        assert_eq!(vec!["safe, fast, productive."], search(query, contents));
+        assert_eq!(
+            vec!["safe, fast, productive."],
+            lines(search(query, contents))
+        );
     }
 
     #[test]
@@ -124,7 +1034,463 @@ Trust me.";
 
         assert_eq!(
             vec!["Rust:", "Trust me."],
-            search_case_insensitive(query, contents)
+            lines(search_case_insensitive(query, contents))
+        );
+    }
+
+    #[test]
+    fn full_case_folding() {
+        let contents = "Die STRASSE, die Straße\nİstanbul";
+
+        assert_eq!(
+            vec![vec![4..11, 17..24]],
+            search_case_insensitive("straße", contents)
+                .map(|result| result.submatches)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["İstanbul"],
+            lines(search_case_insensitive("i\u{307}stanbul", contents))
+        );
+    }
+
+    #[test]
+    fn regex_anchors_and_classes() {
+        let pattern = Regex::new(r"^(fn|pub fn) \w+\(").unwrap();
+        let contents = "\
+fn main() {
+    run();
+}
+pub fn run() {}";
+
+        assert_eq!(
+            vec!["fn main() {", "pub fn run() {}"],
+            lines(search_regex(&pattern, contents))
+        );
+    }
+
+    #[test]
+    fn regex_submatches_span_whole_matches() {
+        let pattern = Regex::new(r"id=(\d+)(?<suffix>[a-z])?").unwrap();
+        let contents = "GET id=17 id=2b done";
+
+        assert_eq!(
+            vec![vec![4..9, 10..15]],
+            search_regex(&pattern, contents)
+                .map(|result| result.submatches)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn collects_files_recursively() {
+        let root = env::temp_dir().join(format!("minigrep-walk-{}", std::process::id()));
+        fs::create_dir_all(root.join("nested")).unwrap();
+        fs::write(root.join("b.txt"), "").unwrap();
+        fs::write(root.join("nested").join("a.txt"), "").unwrap();
+
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+        collect_files(&root, &mut files, &mut errors);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(errors.is_empty());
+
+        assert_eq!(
+            vec![root.join("b.txt"), root.join("nested").join("a.txt")],
+            files
+        );
+    }
+
+    #[test]
+    fn defaults_to_stdin() {
+        let args = ["minigrep", "frog"].map(String::from).into_iter();
+        let config = Config::build(args).unwrap();
+
+        assert_eq!(vec![STDIN_PATH], config.file_paths);
+    }
+
+    #[test]
+    fn match_positions() {
+        let query = "o";
+        let contents = "I'm nobody! Who are you?\r\nAre you nobody, too?\n";
+
+        assert_eq!(
+            vec![
+                Match {
+                    line_number: 1,
+                    line_range: 0..24,
+                    submatches: vec![5..6, 7..8, 14..15, 21..22],
+                    line: b"I'm nobody! Who are you?",
+                    source: None,
+                },
+                Match {
+                    line_number: 2,
+                    line_range: 26..46,
+                    submatches: vec![5..6, 9..10, 11..12, 17..18, 18..19],
+                    line: b"Are you nobody, too?",
+                    source: None,
+                },
+            ],
+            search(query, contents).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn context_flags() {
+        let args = ["minigrep", "frog", "-C", "2", "-A", "1", "poem.txt"].map(String::from);
+        let config = Config::build(args.into_iter()).unwrap();
+
+        assert_eq!((2, 1), (config.before_context, config.after_context));
+        assert_eq!(vec!["poem.txt"], config.file_paths);
+    }
+
+    #[test]
+    fn smart_case() {
+        let ignore_case = |args: &[&str]| {
+            let args = ["minigrep"].iter().chain(args).map(|arg| arg.to_string());
+            Config::build(args).unwrap().ignore_case
+        };
+
+        assert!(ignore_case(&["-S", "frog"]));
+        assert!(!ignore_case(&["-S", "Frog"]));
+        assert!(ignore_case(&["-SE", r"\Sfrog"]));
+        assert!(ignore_case(&["-S", "-i", "Frog"]));
+        assert!(!ignore_case(&["-i", "-S", "Frog"]));
+        assert!(!ignore_case(&["-i", "--no-ignore-case", "frog"]));
+    }
+
+    #[test]
+    fn inverted() {
+        let query = "rUsT";
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+
+        assert_eq!(
+            vec!["safe, fast, productive.", "Pick three."],
+            lines(invert(contents, search_case_insensitive(query, contents)))
+        );
+    }
+
+    #[test]
+    fn options_anywhere() {
+        let args = [
+            "minigrep",
+            "-in",
+            "frog",
+            "--context=1",
+            "poem.txt",
+            "-A3",
+            "--",
+            "-v",
+        ];
+        let config = Config::build(args.map(String::from).into_iter()).unwrap();
+
+        assert!(config.ignore_case && config.line_number && !config.invert_match);
+        assert_eq!((1, 3), (config.before_context, config.after_context));
+        assert_eq!(vec!["frog"], config.patterns);
+        assert_eq!(vec!["poem.txt", "-v"], config.file_paths);
+    }
+
+    #[test]
+    fn several_patterns() {
+        let file = env::temp_dir().join(format!("minigrep-patterns-{}", std::process::id()));
+        fs::write(&file, "toad\nnewt\n").unwrap();
+        let args = [
+            "minigrep",
+            "-e",
+            "frog",
+            "poem.txt",
+            "-f",
+            file.to_str().unwrap(),
+        ];
+        let config = Config::build(args.map(String::from).into_iter());
+        fs::remove_file(&file).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(vec!["frog", "toad", "newt"], config.patterns);
+        assert_eq!(vec!["poem.txt"], config.file_paths);
+    }
+
+    #[test]
+    fn unknown_option() {
+        let args = ["minigrep", "-nj", "frog"].map(String::from).into_iter();
+
+        match Config::build(args) {
+            Err(Error::Argument(message)) => assert_eq!("Unknown option '-j'", message),
+            _ => panic!("expected an argument error"),
+        }
+    }
+
+    #[test]
+    fn unknown_encoding() {
+        let args = ["minigrep", "--encoding=klingon", "frog"].map(String::from);
+
+        match Config::build(args.into_iter()) {
+            Err(Error::Encoding(label)) => assert_eq!("klingon", label),
+            _ => panic!("expected an encoding error"),
+        }
+        assert_eq!(
+            Some(encoding_rs::WINDOWS_1252),
+            config(&["--encoding=latin1", "frog"]).encoding
+        );
+    }
+
+    #[test]
+    fn io_error_keeps_path_and_source() {
+        let config = Config::build(["minigrep", "frog"].map(String::from).into_iter()).unwrap();
+        let err = config
+            .search_path(
+                Path::new("no-such-file.txt"),
+                &Matcher::new(&config).unwrap(),
+                &mut Printer::new(&config, false, io::sink()),
+            )
+            .unwrap()
+            .unwrap_err();
+
+        assert_eq!(
+            "no-such-file.txt: No such file or directory (os error 2)",
+            err.to_string()
+        );
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn broken_pipes_stop_quietly() {
+        let failed = |kind| Err(io::Error::from(kind));
+
+        assert!(!stopped_early(Ok(())).unwrap());
+        assert!(stopped_early(failed(io::ErrorKind::BrokenPipe)).unwrap());
+        match stopped_early(failed(io::ErrorKind::WriteZero)) {
+            Err(Error::Io { path, .. }) => assert_eq!(Path::new(STDOUT_NAME), path),
+            other => panic!("expected an error writing to stdout, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn counts_and_file_names() {
+        let toad = env::temp_dir().join(format!("minigrep-toad-{}", std::process::id()));
+        fs::write(&toad, "a toad\nanother toad\n").unwrap();
+        let files = [PathBuf::from("poem.txt"), toad.clone()];
+        let run = |args: &[&str]| run_on(args, &files).0;
+        let outputs = [
+            run(&["-c", "nobody"]),
+            run(&["-l", "nobody"]),
+            run(&["-L", "nobody"]),
+            run(&["-l", "-c", "nobody"]),
+        ];
+        fs::remove_file(&toad).unwrap();
+        let toad = toad.display();
+
+        assert_eq!(
+            [
+                format!("poem.txt:2\n{toad}:0\n"),
+                String::from("poem.txt\n"),
+                format!("{toad}\n"),
+                String::from("poem.txt\n"),
+            ],
+            outputs
+        );
+    }
+
+    #[test]
+    fn exit_status_and_early_stops() {
+        let selected = |args: &[&str]| {
+            let config = config(args);
+            let matcher = Matcher::new(&config).unwrap();
+            let mut printer = Printer::new(&config, false, io::sink());
+            config
+                .search_path(Path::new("poem.txt"), &matcher, &mut printer)
+                .unwrap()
+                .unwrap()
+        };
+        let files = [PathBuf::from("poem.txt"), PathBuf::from("no-such-file.txt")];
+
+        // -l and -q stop reading at the first selected line
+        assert_eq!(2, selected(&["-c", "nobody"]));
+        assert_eq!(1, selected(&["-l", "nobody"]));
+        assert_eq!(1, selected(&["-q", "nobody"]));
+
+        // -q prints nothing and doesn't even open the files after a selected line
+        assert_eq!((String::new(), true, 0), run_on(&["-q", "nobody"], &files));
+        assert_eq!(1, run_on(&["-c", "nobody"], &files).2);
+
+        // main exits with 1 when nothing is selected
+        assert!(!Config::run(config(&["-q", "toad", "poem.txt"])).unwrap());
+        assert!(Config::run(config(&["-q", "frog", "poem.txt"])).unwrap());
+    }
+
+    #[test]
+    fn json_events() {
+        let (output, selected, _) = run_on(&["--json", "-A", "1", "frog"], &["poem.txt".into()]);
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let path = serde_json::json!({ "text": "poem.txt" });
+
+        assert!(selected);
+        assert_eq!(
+            vec!["begin", "match", "context", "end", "summary"],
+            events
+                .iter()
+                .map(|event| event["type"].as_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(serde_json::json!({ "path": path }), events[0]["data"]);
+        assert_eq!(
+            serde_json::json!({
+                "path": path,
+                "line": { "text": "How public, like a frog" },
+                "line_number": 7,
+                "absolute_offset": 142,
+                "submatches": [{ "match": { "text": "frog" }, "start": 19, "end": 23 }],
+            }),
+            events[1]["data"]
+        );
+        assert_eq!(8, events[2]["data"]["line_number"]);
+        assert_eq!(serde_json::json!([]), events[2]["data"]["submatches"]);
+        assert_eq!(path, events[3]["data"]["path"]);
+        assert_eq!(false, events[3]["data"]["binary_matched"]);
+        assert_eq!(1, events[3]["data"]["stats"]["matched_lines"]);
+        assert_eq!(1, events[3]["data"]["stats"]["matches"]);
+        assert_eq!(1, events[4]["data"]["stats"]["searches_with_match"]);
+
+        let args = ["minigrep", "--json", "-c", "frog"].map(String::from);
+        assert!(matches!(
+            Config::build(args.into_iter()),
+            Err(Error::Argument(_))
+        ));
+    }
+
+    #[test]
+    fn replacements_keep_match_positions() {
+        let files = [PathBuf::from("poem.txt")];
+        let run = |args: &[&str]| run_on(args, &files).0;
+
+        assert_eq!(
+            "146:X\n161:X\n",
+            run(&["-b", "-o", "-r", "X", "-e", "public", "-e", "frog"])
+        );
+        assert_eq!(
+            "7:5:How X, like a X\n",
+            run(&["-n", "--column", "-r", "X", "-e", "public", "-e", "frog"])
+        );
+
+        let output = run(&["--json", "-r", "<$1>", "-E", "fr(o)g"]);
+        let event: serde_json::Value =
+            serde_json::from_str(output.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({ "text": "How public, like a frog" }),
+            event["data"]["line"]
+        );
+        assert_eq!(
+            serde_json::json!([{
+                "match": { "text": "frog" },
+                "start": 19,
+                "end": 23,
+                "replacement": { "text": "<o>" },
+            }]),
+            event["data"]["submatches"]
+        );
+    }
+
+    #[test]
+    fn json_ends_every_source() {
+        let types = |output: &str| {
+            output
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .map(|event| event["type"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // A directory opens, but reading it fails once the search has begun
+        let (output, _, unsearched) = run_on(&["--json", "frog"], &[env::temp_dir()]);
+        assert_eq!(1, unsearched);
+        assert_eq!(vec!["begin", "end", "summary"], types(&output));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn json_keeps_paths_that_arent_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        use base64::prelude::{Engine, BASE64_STANDARD};
+
+        let name = format!("minigrep-{}-", std::process::id());
+        let name = [name.as_bytes(), b"\xff.txt"].concat();
+        let file = env::temp_dir().join(std::ffi::OsStr::from_bytes(&name));
+        fs::write(&file, "a frog\n").unwrap();
+        let (output, _, _) = run_on(&["--json", "frog"], slice::from_ref(&file));
+        fs::remove_file(&file).unwrap();
+        let begin: serde_json::Value =
+            serde_json::from_str(output.lines().next().unwrap()).unwrap();
+
+        assert_eq!(
+            serde_json::json!({ "bytes": BASE64_STANDARD.encode(file.as_os_str().as_bytes()) }),
+            begin["data"]["path"]
+        );
+    }
+
+    #[test]
+    fn keeps_searching_past_bad_paths() {
+        let run = |args: &[&str]| {
+            let args = iter::once("minigrep").chain(args.iter().copied());
+            Config::run(Config::build(args.map(String::from)).unwrap())
+        };
+
+        match run(&[
+            "-c",
+            "nobody",
+            "no-such-file.txt",
+            "poem.txt",
+            "no-such-dir/",
+        ]) {
+            Err(Error::Unsearched(2)) => {}
+            other => panic!("expected 2 unsearched paths, got {other:?}"),
+        }
+        assert!(run(&["-q", "nobody", "no-such-file.txt", "poem.txt"]).unwrap());
+    }
+
+    #[test]
+    fn searches_invalid_utf8() {
+        let contents = b"caf\xe9 au lait\nbrown fox\n";
+
+        assert_eq!(
+            vec![b"caf\xe9 au lait".as_slice()],
+            search("au", contents)
+                .map(|result| result.line)
+                .collect::<Vec<_>>()
+        );
+    }
+    #[test]
+    fn offsets_into_decoded_files() {
+        let file = env::temp_dir().join(format!("minigrep-utf16-{}", std::process::id()));
+        let utf16: Vec<u8> = "\u{feff}hi frog\nsecond frog\n"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        fs::write(&file, utf16).unwrap();
+        let files = [file.clone()];
+        let outputs = [
+            run_on(&["-b", "-o", "frog", "--no-mmap"], &files).0,
+            run_on(&["-b", "-o", "frog", "--mmap"], &files).0,
+            run_on(&["-b", "frog"], &files).0,
+        ];
+        fs::remove_file(&file).unwrap();
+
+        // The BOM is two bytes and every character after it another two
+        assert_eq!(
+            [
+                String::from("8:frog\n32:frog\n"),
+                String::from("8:frog\n32:frog\n"),
+                String::from("2:hi frog\n18:second frog\n"),
+            ],
+            outputs
         );
     }
 }
@@ -1,17 +1,32 @@
 use std::env;
 use std::process;
-use minigrep::Config;
+use minigrep::{Config, Error};
 
 
-/// Gathers args from command line and performs grep-like search
+/// Gathers args from command line and performs grep-like search, exiting
+/// with 0 if a line was selected, 1 if none were and 2 on error
 fn main() {
-    let config = Config::build(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {err}");
-        process::exit(1);
-    });
+    let config = Config::build(env::args()).unwrap_or_else(|err| exit_with(err));
 
-    if let Err(e) = Config::run(config){
-        eprintln!("Application error: {e}");
-        process::exit(1);
+    match Config::run(config) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(err) => exit_with(err),
     }
-}
\ No newline at end of file
+}
+
+/// Explains an error on stderr and exits with grep's error status
+fn exit_with(err: Error) -> ! {
+    match err {
+        Error::Argument(_) | Error::Encoding(_) => {
+            eprintln!("Problem parsing arguments: {err}");
+            eprintln!("Try 'minigrep --help' for more information.");
+        }
+        Error::Pattern(source) => eprintln!("Problem with the query pattern: {source}"),
+        Error::Patterns(source) => eprintln!("Problem with the query patterns: {source}"),
+        Error::Io { .. } => eprintln!("Application error: {err}"),
+        // Each path was already reported as it was skipped
+        Error::Unsearched(_) => {}
+    }
+    process::exit(2);
+}
@@ -0,0 +1,757 @@
+use std::cmp::Reverse;
+use std::iter;
+use std::ops::Range;
+use std::str;
+
+use aho_corasick::automaton::Automaton;
+use aho_corasick::dfa::DFA;
+use aho_corasick::{AhoCorasick, Anchored, MatchKind, StartKind};
+use caseless::Caseless;
+use memchr::arch::all::packedpair::Pair;
+use memchr::memmem;
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::{CaseFolding, Config, Error};
+
+/// A compiled query that finds every submatch within a single line
+///
+/// Lines are raw bytes, so input that isn't valid UTF-8 can still be searched.
+/// Several patterns are searched for at once with Aho-Corasick, preferring the
+/// longest where more than one matches at the same place, like grep.
+pub(crate) struct Matcher {
+    search: Search,
+    bounds: Bounds,
+    /// True if the patterns are regular expressions, whose groups -r can use.
+    /// Literal queries can be searched for with a regular expression too.
+    captures: bool,
+}
+
+/// How candidate submatches are found, before checking their bounds
+enum Search {
+    /// Plain substring search
+    Literal(Box<memmem::Finder<'static>>),
+    /// Substring search for any of several queries
+    Literals(AhoCorasick),
+    /// Substring search that ignores case, holding the case folded query and
+    /// the index of its rarest byte
+    CaseInsensitive { query: String, rare: usize },
+    /// Substring search for any of several queries that ignores case, holding
+    /// the case folded queries and an automaton for searching ASCII lines
+    CaseInsensitiveLiterals {
+        queries: Box<FoldedQueries>,
+        ascii: AhoCorasick,
+    },
+    /// Regular expression search, with case sensitivity built in
+    Regex(Regex),
+}
+
+/// Several case folded queries, compiled for finding them in lines that aren't
+/// all ASCII
+/// automaton: an anchored automaton over the folded queries, which a line is
+/// folded into a character at a time from each place a submatch may start, so
+/// that every query is tried at once
+/// first_bytes: which ASCII bytes a query can start with, in lowercase
+/// has_empty: true if one of the queries is empty
+struct FoldedQueries {
+    automaton: DFA,
+    first_bytes: [bool; 128],
+    has_empty: bool,
+}
+
+/// Where submatches have to start and end
+/// Anywhere: the default, so any substring will do
+/// Words: with no word character just before or after, set by -w
+/// Line: at the start and end of the line, set by -x
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Bounds {
+    Anywhere,
+    Words,
+    Line,
+}
+
+/// Finds where the next line that may match is, so that whole buffers can be
+/// scanned rather than searching line by line
+pub(crate) enum Prefilter<'m> {
+    Literal(&'m memmem::Finder<'static>),
+    Literals(&'m AhoCorasick),
+}
+
+impl Prefilter<'_> {
+    /// Byte offset of the first candidate in a haystack
+    pub(crate) fn find(&self, haystack: &[u8]) -> Option<usize> {
+        match self {
+            Prefilter::Literal(finder) => finder.find(haystack),
+            Prefilter::Literals(automaton) => automaton.find(haystack).map(|found| found.start()),
+        }
+    }
+}
+
+impl Matcher {
+    /// Compiles the patterns of a Config the way its options ask for
+    ///
+    /// The regex crate folds case the simple way, so it also handles literal
+    /// queries when simple folding is asked for. Several regular expressions
+    /// are joined into one alternation, and -w and -x are built into it.
+    pub(crate) fn new(config: &Config) -> Result<Matcher, Error> {
+        let patterns = config.patterns.as_slice();
+        let simple_folding = config.ignore_case && config.case_folding == CaseFolding::Simple;
+        // As in grep, -x wins over -w
+        let bounds = if config.line_regexp {
+            Bounds::Line
+        } else if config.word_regexp {
+            Bounds::Words
+        } else {
+            Bounds::Anywhere
+        };
+
+        let search = if patterns.is_empty() {
+            // Only an empty set of literals matches nothing at all
+            literals(patterns)?
+        } else if config.regex || simple_folding {
+            let pattern = match (patterns, config.regex) {
+                ([pattern], true) => pattern.clone(),
+                (patterns, true) => patterns
+                    .iter()
+                    .map(|pattern| format!("(?:{pattern})"))
+                    .collect::<Vec<_>>()
+                    .join("|"),
+                // The first alternative that matches wins, so the longest
+                // literals go first to find the longest match at each start,
+                // as the other searches do. Simple folding maps characters
+                // one to one, so that's counted in characters.
+                (patterns, false) => {
+                    let mut patterns: Vec<_> = patterns.iter().collect();
+                    patterns.sort_by_key(|pattern| Reverse(pattern.chars().count()));
+                    patterns
+                        .into_iter()
+                        .map(|pattern| regex::escape(pattern))
+                        .collect::<Vec<_>>()
+                        .join("|")
+                }
+            };
+            let pattern = match bounds {
+                Bounds::Anywhere => pattern,
+                Bounds::Words => format!(r"\b{{start-half}}(?:{pattern})\b{{end-half}}"),
+                Bounds::Line => format!("^(?:{pattern})$"),
+            };
+            let pattern = RegexBuilder::new(&pattern)
+                .case_insensitive(config.ignore_case)
+                .build()?;
+            return Ok(Matcher {
+                captures: config.regex,
+                ..Matcher::regex(pattern)
+            });
+        } else if config.ignore_case {
+            match patterns {
+                [query] => case_insensitive(query),
+                queries => case_insensitive_literals(queries)?,
+            }
+        } else {
+            match patterns {
+                [query] => literal(query),
+                queries => literals(queries)?,
+            }
+        };
+        Ok(Matcher {
+            search,
+            bounds,
+            captures: false,
+        })
+    }
+
+    pub(crate) fn literal(query: &str) -> Matcher {
+        Matcher::anywhere(literal(query))
+    }
+
+    /// Matches regardless of case using full case folding
+    pub(crate) fn case_insensitive(query: &str) -> Matcher {
+        Matcher::anywhere(case_insensitive(query))
+    }
+
+    pub(crate) fn regex(pattern: Regex) -> Matcher {
+        Matcher::anywhere(Search::Regex(pattern))
+    }
+
+    fn anywhere(search: Search) -> Matcher {
+        Matcher {
+            captures: matches!(search, Search::Regex(_)),
+            search,
+            bounds: Bounds::Anywhere,
+        }
+    }
+
+    /// A prefilter that finds every match, letting whole buffers be scanned
+    /// for lines that may match instead of searching line by line
+    pub(crate) fn prefilter(&self) -> Option<Prefilter<'_>> {
+        match &self.search {
+            Search::Literal(finder) => Some(Prefilter::Literal(finder)),
+            Search::Literals(automaton) => Some(Prefilter::Literals(automaton)),
+            _ => None,
+        }
+    }
+
+    /// Byte ranges of every non-overlapping submatch in a line, in order
+    ///
+    /// A candidate that isn't within its bounds is skipped, and the search
+    /// carries on from the byte after its start, so it can't hide a later
+    /// candidate that overlaps it.
+    pub(crate) fn find_all(&self, line: &[u8]) -> Vec<Range<usize>> {
+        if let Search::Regex(pattern) = &self.search {
+            return pattern.find_iter(line).map(|found| found.range()).collect();
+        }
+        // ASCII only folds to ASCII, so ASCII lines can skip Unicode tables
+        let ascii = line.is_ascii();
+
+        if self.bounds == Bounds::Line {
+            return self
+                .find_from(line, 0, ascii)
+                .filter(|found| *found == (0..line.len()))
+                .into_iter()
+                .collect();
+        }
+
+        let mut submatches = Vec::new();
+        let mut at = 0;
+        while at <= line.len() {
+            let Some(found) = self.find_from(line, at, ascii) else {
+                break;
+            };
+            let next = found.start + 1;
+            let found = match self.bounds {
+                Bounds::Words if !is_word(line, &found) => self.shorter_word(line, &found),
+                _ => Some(found),
+            };
+            if let Some(found) = found {
+                // An empty submatch matches between every byte, so step past it
+                at = found.end.max(next);
+                submatches.push(found);
+            } else {
+                at = next;
+            }
+        }
+        submatches
+    }
+
+    /// Writes a line to "dst" with every submatch replaced, for -r, and returns
+    /// where each replacement ended up
+    ///
+    /// When the patterns are regular expressions, $1 or ${name} in the
+    /// replacement expands to what that group captured, as Regex::replace
+    /// does; otherwise the replacement is used as it is.
+    ///
+    /// # Arguments
+    ///
+    /// * "line" - the line the submatches were found in
+    /// * "submatches" - every submatch in the line, as found by find_all
+    /// * "replacement" - the text to put in place of each submatch
+    /// * "dst" - the buffer the replaced line is appended to
+    pub(crate) fn replace(
+        &self,
+        line: &[u8],
+        submatches: &[Range<usize>],
+        replacement: &[u8],
+        dst: &mut Vec<u8>,
+    ) -> Vec<Range<usize>> {
+        let mut replaced = Vec::with_capacity(submatches.len());
+        let mut written = 0;
+        for submatch in submatches {
+            dst.extend_from_slice(&line[written..submatch.start]);
+            let start = dst.len();
+            match &self.search {
+                // Searching again from the start of a submatch finds it again,
+                // this time with its groups
+                Search::Regex(pattern) if self.captures => {
+                    if let Some(captures) = pattern.captures_at(line, submatch.start) {
+                        captures.expand(replacement, dst);
+                    }
+                }
+                _ => dst.extend_from_slice(replacement),
+            }
+            replaced.push(start..dst.len());
+            written = submatch.end;
+        }
+        dst.extend_from_slice(&line[written..]);
+        replaced
+    }
+
+    /// The longest submatch that starts where "found" does but is shorter, and
+    /// that is a whole word
+    ///
+    /// With several queries, one may be a word where a longer one starting at
+    /// the same place isn't, as "ab" is in "ab-cd" when "ab-c" is a query too.
+    fn shorter_word(&self, line: &[u8], found: &Range<usize>) -> Option<Range<usize>> {
+        let start = found.start;
+        match &self.search {
+            Search::Literals(automaton) => {
+                let mut end = found.end;
+                while end > start {
+                    let input = aho_corasick::Input::new(line)
+                        .span(start..end - 1)
+                        .anchored(Anchored::Yes);
+                    let shorter = automaton.find(input)?.range();
+                    if is_word(line, &shorter) {
+                        return Some(shorter);
+                    }
+                    end = shorter.end;
+                }
+                None
+            }
+            Search::CaseInsensitiveLiterals { queries, .. } => {
+                let text = line[start..].utf8_chunks().next()?.valid();
+                let is_shorter_word =
+                    |len| len < found.len() && is_word(line, &(start..start + len));
+                queries
+                    .longest_match_len(text, is_shorter_word)
+                    .or_else(|| {
+                        queries
+                            .has_empty
+                            .then_some(0)
+                            .filter(|&len| is_shorter_word(len))
+                    })
+                    .map(|len| start..start + len)
+            }
+            _ => None,
+        }
+    }
+
+    /// The leftmost candidate submatch at or after "at", preferring the longest
+    /// where several start there
+    fn find_from(&self, line: &[u8], at: usize, ascii: bool) -> Option<Range<usize>> {
+        let haystack = &line[at..];
+        let found = match &self.search {
+            Search::Literal(finder) => finder
+                .find(haystack)
+                .map(|start| start..start + finder.needle().len()),
+            Search::Literals(automaton) => automaton.find(haystack).map(|found| found.range()),
+            Search::CaseInsensitive { query, .. } if query.is_empty() => Some(0..0),
+            Search::CaseInsensitive { query, rare } if ascii => {
+                find_ascii_case_insensitive(query.as_bytes(), *rare, haystack)
+            }
+            Search::CaseInsensitive { query, .. } => {
+                let first = query.as_bytes()[0];
+                find_folded(
+                    haystack,
+                    |byte| !byte.is_ascii() || byte.to_ascii_lowercase() == first,
+                    |text| folded_match_len(text, query.as_bytes()),
+                )
+            }
+            Search::CaseInsensitiveLiterals {
+                ascii: automaton, ..
+            } if ascii => automaton.find(haystack).map(|found| found.range()),
+            Search::CaseInsensitiveLiterals { queries, .. } => {
+                let found = find_folded(
+                    haystack,
+                    |byte| queries.may_start(byte),
+                    |text| queries.longest_match_len(text, |_| true),
+                );
+                // An empty query matches right away, so only a longer
+                // submatch starting at the same place beats it
+                if queries.has_empty && found.as_ref().is_none_or(|found| found.start > 0) {
+                    Some(0..0)
+                } else {
+                    found
+                }
+            }
+            Search::Regex(pattern) => return pattern.find_at(line, at).map(|found| found.range()),
+        };
+        found.map(|found| at + found.start..at + found.end)
+    }
+}
+
+fn literal(query: &str) -> Search {
+    Search::Literal(Box::new(memmem::Finder::new(query.as_bytes()).into_owned()))
+}
+
+/// Matches any of several queries
+///
+/// The automaton can also be anchored, to look for shorter queries at the
+/// start of a candidate that isn't a whole word.
+fn literals(queries: &[String]) -> Result<Search, Error> {
+    let automaton = AhoCorasick::builder()
+        .match_kind(MatchKind::LeftmostLongest)
+        .start_kind(StartKind::Both)
+        .build(queries)?;
+    Ok(Search::Literals(automaton))
+}
+
+fn case_insensitive(query: &str) -> Search {
+    let query = caseless::default_case_fold_str(query);
+    let rare = Pair::new(query.as_bytes()).map_or(0, |pair| usize::from(pair.index1()));
+    Search::CaseInsensitive { query, rare }
+}
+
+/// Matches any of several queries regardless of case using full case folding
+fn case_insensitive_literals(queries: &[String]) -> Result<Search, Error> {
+    let queries: Vec<String> = queries
+        .iter()
+        .map(|query| caseless::default_case_fold_str(query))
+        .collect();
+    let ascii = AhoCorasick::builder()
+        .match_kind(MatchKind::LeftmostLongest)
+        .ascii_case_insensitive(true)
+        .build(&queries)?;
+    let queries = Box::new(FoldedQueries::new(&queries)?);
+    Ok(Search::CaseInsensitiveLiterals { queries, ascii })
+}
+
+impl FoldedQueries {
+    fn new(queries: &[String]) -> Result<FoldedQueries, Error> {
+        // Anchored at the place being tried, and with standard semantics so
+        // that no query is left out of the automaton
+        let automaton = DFA::builder()
+            .match_kind(MatchKind::Standard)
+            .start_kind(StartKind::Anchored)
+            .build(queries)?;
+        let mut first_bytes = [false; 128];
+        for query in queries {
+            if let Some(&first) = query.as_bytes().first().filter(|first| first.is_ascii()) {
+                first_bytes[usize::from(first)] = true;
+            }
+        }
+        Ok(FoldedQueries {
+            automaton,
+            first_bytes,
+            has_empty: queries.iter().any(String::is_empty),
+        })
+    }
+
+    /// Whether a submatch may start with a byte. ASCII only folds to ASCII,
+    /// so the only ASCII bytes that can are the two cases of the first byte
+    /// of a query.
+    fn may_start(&self, byte: &u8) -> bool {
+        !byte.is_ascii() || self.first_bytes[usize::from(byte.to_ascii_lowercase())]
+    }
+
+    /// How many bytes from the start of "text" fold to the longest query that
+    /// they fold to exactly, of the lengths that "accept" takes, if any
+    ///
+    /// Each character is folded and fed through the automaton whole, and
+    /// matches are only checked for between characters, so a submatch can't
+    /// end halfway through what one character folds to, as "s" would in "ß".
+    fn longest_match_len(&self, text: &str, accept: impl Fn(usize) -> bool) -> Option<usize> {
+        let automaton = &self.automaton;
+        let mut state = automaton.start_state(Anchored::Yes).ok()?;
+        let mut folded_len = 0;
+        let mut longest = None;
+
+        for (index, character) in text.char_indices() {
+            let mut encoded = [0; 4];
+            if character.is_ascii() {
+                let lower = character.to_ascii_lowercase() as u8;
+                state = automaton.next_state(Anchored::Yes, state, lower);
+                folded_len += 1;
+            } else {
+                for folded in iter::once(character).default_case_fold() {
+                    for &byte in folded.encode_utf8(&mut encoded).as_bytes() {
+                        state = automaton.next_state(Anchored::Yes, state, byte);
+                    }
+                    folded_len += folded.len_utf8();
+                }
+            }
+            if automaton.is_dead(state) {
+                break;
+            }
+            // A state also holds the queries that are suffixes of what was fed
+            // through, which don't start at the start of the text
+            let matched = automaton.is_match(state)
+                && (0..automaton.match_len(state)).any(|index| {
+                    automaton.pattern_len(automaton.match_pattern(state, index)) == folded_len
+                });
+            let len = index + character.len_utf8();
+            if matched && accept(len) {
+                longest = Some(len);
+            }
+        }
+        longest
+    }
+}
+
+/// Whether a submatch is a whole word, with no word character just before or
+/// just after it, like \b{start-half} and \b{end-half} in a regular expression
+fn is_word(line: &[u8], found: &Range<usize>) -> bool {
+    // The character before is the shortest valid UTF-8 that ends there
+    let before = (1..=found.start.min(4))
+        .find_map(|len| str::from_utf8(&line[found.start - len..found.start]).ok())
+        .and_then(|text| text.chars().next());
+    let after = line[found.end..]
+        .utf8_chunks()
+        .next()
+        .and_then(|chunk| chunk.valid().chars().next());
+    !before.is_some_and(is_word_character) && !after.is_some_and(is_word_character)
+}
+
+/// Letters, digits and underscores in any script, as \w matches them
+fn is_word_character(character: char) -> bool {
+    character.is_alphanumeric() || character == '_'
+}
+
+/// The first occurrence of a folded query in a line that is all ASCII, where
+/// folding is just lowercasing
+///
+/// Candidates are found with memchr2 on both cases of the rarest byte of the
+/// query, at index "rare", then checked in place, so nothing is allocated.
+fn find_ascii_case_insensitive(query: &[u8], rare: usize, line: &[u8]) -> Option<Range<usize>> {
+    // Text that folds to something outside ASCII can't be in an ASCII line
+    if !query.is_ascii() {
+        return None;
+    }
+    let (lower, upper) = (
+        query[rare].to_ascii_lowercase(),
+        query[rare].to_ascii_uppercase(),
+    );
+    let mut at = 0;
+
+    while at + query.len() <= line.len() {
+        let start = at + memchr::memchr2(lower, upper, &line[at + rare..])?;
+        let end = start + query.len();
+        if end > line.len() {
+            return None;
+        }
+        if line[start..end].eq_ignore_ascii_case(query) {
+            return Some(start..end);
+        }
+        at = start + 1;
+    }
+    None
+}
+
+/// The first submatch of folded queries in a line, as a byte range of the
+/// original line
+///
+/// Characters are folded one at a time while comparing, rather than folding a
+/// copy of the line, so the range needs no mapping back to the original.
+/// Submatches have to start and end on whole characters, so "s" doesn't match
+/// half of "ß". Bytes that aren't valid UTF-8 never match.
+///
+/// # Arguments
+///
+/// * "line" - the line to search
+/// * "may_start" - whether a submatch may start with a byte
+/// * "match_len" - the length of the longest submatch at the start of a text
+fn find_folded(
+    line: &[u8],
+    may_start: impl Fn(&u8) -> bool,
+    match_len: impl Fn(&str) -> Option<usize>,
+) -> Option<Range<usize>> {
+    let mut offset = 0;
+
+    for chunk in line.utf8_chunks() {
+        let text = chunk.valid();
+        let mut at = 0;
+        while let Some(skipped) = text.as_bytes()[at..].iter().position(&may_start) {
+            at += skipped;
+            if let Some(len) = match_len(&text[at..]).filter(|&len| len > 0) {
+                return Some(offset + at..offset + at + len);
+            }
+            at += text[at..].chars().next().map_or(1, char::len_utf8);
+        }
+        offset += text.len() + chunk.invalid().len();
+    }
+    None
+}
+
+/// How many bytes from the start of "text" fold to exactly "query", if any
+fn folded_match_len(text: &str, query: &[u8]) -> Option<usize> {
+    let mut remaining = query;
+
+    for (index, character) in text.char_indices() {
+        if remaining.is_empty() {
+            return Some(index);
+        }
+        if character.is_ascii() {
+            remaining = remaining.strip_prefix(&[character.to_ascii_lowercase() as u8])?;
+        } else {
+            for folded in iter::once(character).default_case_fold() {
+                let mut encoded = [0; 4];
+                remaining = remaining.strip_prefix(folded.encode_utf8(&mut encoded).as_bytes())?;
+            }
+        }
+    }
+    remaining.is_empty().then_some(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_folding_maps_one_character_to_one() {
+        let config = Config {
+            patterns: vec![String::from("STRASSE")],
+            ignore_case: true,
+            case_folding: CaseFolding::Simple,
+            ..Config::default()
+        };
+        let matcher = Matcher::new(&config).unwrap();
+
+        assert!(matcher.find_all("Straße".as_bytes()).is_empty());
+        assert_eq!(vec![0..7], matcher.find_all(b"strasse"));
+        assert_eq!(
+            vec![0..2],
+            Matcher::case_insensitive("ss").find_all(b"\xc3\x9fa\xff")
+        );
+    }
+
+    #[test]
+    fn several_patterns_prefer_the_longest() {
+        let matcher = |regex, ignore_case| {
+            let config = Config {
+                patterns: vec![String::from("us"), String::from("of us")],
+                regex,
+                ignore_case,
+                ..Config::default()
+            };
+            Matcher::new(&config).unwrap()
+        };
+        let line = "a pair OF US, thus".as_bytes();
+
+        assert_eq!(
+            vec![7..12, 16..18],
+            matcher(false, false).find_all(b"a pair of us, thus")
+        );
+        assert_eq!(vec![7..12, 16..18], matcher(false, true).find_all(line));
+        assert_eq!(vec![7..12, 16..18], matcher(true, true).find_all(line));
+        assert_eq!(
+            vec![9..14],
+            matcher(false, true).find_all("Straße: OF US".as_bytes())
+        );
+
+        // Simple folding joins the literals into one regular expression, where
+        // the order they're listed in decides which wins
+        for case_folding in [CaseFolding::Full, CaseFolding::Simple] {
+            let config = Config {
+                patterns: vec![String::from("ab"), String::from("aba")],
+                ignore_case: true,
+                case_folding,
+                ..Config::default()
+            };
+            assert_eq!(
+                vec![0..3, 4..7],
+                Matcher::new(&config).unwrap().find_all(b"aba ABA")
+            );
+        }
+    }
+
+    #[test]
+    fn whole_words_and_lines() {
+        let matcher = |patterns: &[&str], regex, ignore_case, line_regexp| {
+            let config = Config {
+                patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+                regex,
+                ignore_case,
+                word_regexp: true,
+                line_regexp,
+                ..Config::default()
+            };
+            Matcher::new(&config).unwrap()
+        };
+        let line = "Trust us, the public: US".as_bytes();
+
+        assert_eq!(
+            vec![6..8],
+            matcher(&["us"], false, false, false).find_all(line)
+        );
+        assert_eq!(
+            vec![6..8, 22..24],
+            matcher(&["us"], false, true, false).find_all(line)
+        );
+        assert_eq!(
+            vec![6..8, 22..24],
+            matcher(&["u."], true, true, false).find_all(line)
+        );
+        assert_eq!(
+            vec![7..9],
+            matcher(&["ss"], false, false, false).find_all("aß_ss ss-".as_bytes())
+        );
+        assert_eq!(
+            vec![6..9],
+            matcher(&["ab", "aba"], false, false, false).find_all(b"ababa aba")
+        );
+        // A shorter query that starts at the same place is still a word
+        for (ignore_case, line) in [(false, "ab-cd ab"), (true, "AB-cd ab"), (true, "ab-cd é")] {
+            assert_eq!(
+                vec![0..2, 6..8],
+                matcher(&["ab", "ab-c", "é"], false, ignore_case, false).find_all(line.as_bytes())
+            );
+        }
+        assert!(matcher(&["us"], false, true, true)
+            .find_all(line)
+            .is_empty());
+        assert_eq!(
+            vec![0..24],
+            matcher(&["trust us, the public: us"], false, true, true).find_all(line)
+        );
+    }
+
+    #[test]
+    fn ascii_and_unicode_lines_fold_alike() {
+        let matcher = Matcher::case_insensitive("StraSSe");
+
+        assert_eq!(
+            vec![4..11, 12..19],
+            matcher.find_all(b"die STRASSE strasse")
+        );
+        assert_eq!(
+            vec![4..11, 12..19],
+            matcher.find_all("die STRASSE Straße".as_bytes())
+        );
+        assert!(matcher.find_all(b"die Stras").is_empty());
+        assert!(Matcher::case_insensitive("é").find_all(b"cafe").is_empty());
+
+        // Several queries are tried at once, and one that ends inside a
+        // longer one that doesn't match still matches from its own start
+        let config = Config {
+            patterns: vec![String::from("BC"), String::from("abcd"), String::from("SS")],
+            ignore_case: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            vec![2..4, 7..9],
+            Matcher::new(&config)
+                .unwrap()
+                .find_all("xabcé ß".as_bytes())
+        );
+    }
+
+    #[test]
+    fn replaces_submatches() {
+        let replace = |config: Config, line: &[u8], replacement: &str| {
+            let matcher = Matcher::new(&config).unwrap();
+            let mut replaced = Vec::new();
+            let submatches = matcher.find_all(line);
+            let ranges = matcher.replace(line, &submatches, replacement.as_bytes(), &mut replaced);
+            (String::from_utf8(replaced).unwrap(), ranges)
+        };
+        let regex = Config {
+            patterns: vec![String::from(r"(?<name>\w+)body")],
+            regex: true,
+            ..Config::default()
+        };
+        let literal = Config {
+            patterns: vec![String::from("$1")],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            (String::from("[some] or [no]"), vec![0..6, 10..14]),
+            replace(regex, b"somebody or nobody", "[${name}]")
+        );
+        assert_eq!(
+            (String::from("cost: $$ or $$"), vec![6..8, 12..14]),
+            replace(literal, b"cost: $1 or $1", "$$")
+        );
+
+        // Simple folding searches with a regular expression, but the query
+        // is still literal, so there are no groups to refer to
+        let simple_folding = Config {
+            patterns: vec![String::from("A")],
+            ignore_case: true,
+            case_folding: CaseFolding::Simple,
+            ..Config::default()
+        };
+        assert_eq!(
+            (String::from("x$1y$$ cost x$1y$$"), vec![0..6, 12..18]),
+            replace(simple_folding, b"a cost a", "x$1y$$")
+        );
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::slice;
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde_json::{json, Value};
+
+use crate::{Config, Match};
+
+/// ANSI escape sequences used to highlight output, matching grep's defaults
+const MATCH_COLOR: &str = "\x1b[1;31m";
+const PATH_COLOR: &str = "\x1b[35m";
+const NUMBER_COLOR: &str = "\x1b[32m";
+const SEPARATOR_COLOR: &str = "\x1b[36m";
+const RESET_COLOR: &str = "\x1b[0m";
+
+/// A line held back in case a later match needs it as before-context
+struct ContextLine {
+    line_number: usize,
+    byte_offset: usize,
+    text: Vec<u8>,
+}
+
+/// Totals across every source searched, for --json's summary
+#[derive(Default)]
+struct Stats {
+    searches: usize,
+    searches_with_match: usize,
+    matched_lines: usize,
+    matches: usize,
+}
+
+/// Prints matching lines for a Config, along with any context lines it asks for
+///
+/// Every line of a source has to be handed over in order, either as a match
+/// or as a candidate context line, so that context windows can be tracked.
+/// Overlapping windows are merged, and a "--" separator is printed between
+/// groups of lines that aren't contiguous, like grep.
+///
+/// With --json, lines are printed as "match" and "context" events instead,
+/// between a "begin" and an "end" event for each source, and a "summary"
+/// event follows the last source.
+///
+/// Output goes to any writer, normally standard output, and every method
+/// returns the error if writing to it fails.
+pub(crate) struct Printer<'c, W: Write> {
+    config: &'c Config,
+    out: W,
+    with_filename: bool,
+    source: Option<Arc<str>>,
+    path_json: Value,
+    before: VecDeque<ContextLine>,
+    after_remaining: usize,
+    last_printed: Option<usize>,
+    printed_any: bool,
+    started: Instant,
+    source_started: Instant,
+    source_matches: usize,
+    binary_matched: bool,
+    stats: Stats,
+}
+
+impl<'c, W: Write> Printer<'c, W> {
+    pub(crate) fn new(config: &'c Config, with_filename: bool, out: W) -> Self {
+        Printer {
+            config,
+            out,
+            with_filename,
+            source: None,
+            path_json: Value::Null,
+            before: VecDeque::with_capacity(config.before_context),
+            after_remaining: 0,
+            last_printed: None,
+            printed_any: false,
+            started: Instant::now(),
+            source_started: Instant::now(),
+            source_matches: 0,
+            binary_matched: false,
+            stats: Stats::default(),
+        }
+    }
+
+    /// True if non-matching lines need to be handed to the printer at all
+    ///
+    /// Context is left out with -o, as grep does.
+    pub(crate) fn wants_context(&self) -> bool {
+        !self.config.only_matching
+            && (self.config.before_context > 0 || self.config.after_context > 0)
+    }
+
+    /// Starts printing lines from a new source, such as the next file
+    ///
+    /// With --json, a path that isn't UTF-8 is kept as the bytes it's made of,
+    /// rather than the lossy version that's printed otherwise.
+    pub(crate) fn begin(&mut self, path: &Path) -> io::Result<()> {
+        self.source = Some(Arc::from(path.display().to_string()));
+        self.path_json = data_json(path.as_os_str().as_encoded_bytes());
+        self.before.clear();
+        self.after_remaining = 0;
+        self.last_printed = None;
+        self.source_started = Instant::now();
+        self.source_matches = 0;
+        self.binary_matched = false;
+        self.emit("begin", json!({ "path": &self.path_json }))
+    }
+
+    /// Finishes the current source, which with --json prints an "end" event
+    /// with how many lines were selected and how many matches they had
+    pub(crate) fn end(&mut self, selected: usize) -> io::Result<()> {
+        self.stats.searches += 1;
+        self.stats.searches_with_match += usize::from(selected > 0);
+        self.stats.matched_lines += selected;
+        self.stats.matches += self.source_matches;
+        self.emit(
+            "end",
+            json!({
+                "path": &self.path_json,
+                "binary_matched": self.binary_matched,
+                "stats": {
+                    "matched_lines": selected,
+                    "matches": self.source_matches,
+                    "elapsed": elapsed_json(self.source_started.elapsed()),
+                },
+            }),
+        )
+    }
+
+    /// Prints a "summary" event with the totals for every source, with --json
+    pub(crate) fn summary(&mut self) -> io::Result<()> {
+        self.emit(
+            "summary",
+            json!({
+                "elapsed_total": elapsed_json(self.started.elapsed()),
+                "stats": {
+                    "searches": self.stats.searches,
+                    "searches_with_match": self.stats.searches_with_match,
+                    "matched_lines": self.stats.matched_lines,
+                    "matches": self.stats.matches,
+                },
+            }),
+        )
+    }
+
+    /// Prints a matching line, preceded by whatever before-context is pending
+    ///
+    /// With -o, each non-empty submatch is printed on its own line instead,
+    /// with its own column and byte offset.
+    ///
+    /// # Arguments
+    ///
+    /// * "result" - the line that matched
+    /// * "replaced" - with -r, the line with every submatch replaced and where each replacement is in it
+    /// * "input_offset" - turns an offset within the line into one within the input, which takes more than an addition if the input was decoded
+    pub(crate) fn matched(
+        &mut self,
+        result: &Match,
+        replaced: Option<(&[u8], &[Range<usize>])>,
+        input_offset: impl Fn(usize) -> usize,
+    ) -> io::Result<()> {
+        self.source_matches += result.submatches.len();
+        if self.config.only_matching {
+            for (index, submatch) in result.submatches.iter().enumerate() {
+                if submatch.is_empty() {
+                    continue;
+                }
+                let replacement =
+                    replaced.map(|(line, replacements)| &line[replacements[index].clone()]);
+                let whole = replacement.map(|replacement| 0..replacement.len());
+                self.print_line(
+                    result.line_number,
+                    input_offset(submatch.start),
+                    Some(submatch.start + 1),
+                    &result.line[submatch.clone()],
+                    slice::from_ref(&(0..submatch.len())),
+                    replacement
+                        .zip(whole.as_ref())
+                        .map(|(replacement, whole)| (replacement, slice::from_ref(whole))),
+                )?;
+            }
+            return Ok(());
+        }
+        while let Some(line) = self.before.pop_front() {
+            self.print_line(
+                line.line_number,
+                line.byte_offset,
+                None,
+                &line.text,
+                &[],
+                None,
+            )?;
+        }
+        self.after_remaining = self.config.after_context;
+        self.print_line(
+            result.line_number,
+            result.byte_offset(),
+            Some(result.column()),
+            result.line,
+            &result.submatches,
+            replaced,
+        )
+    }
+
+    /// Handles a line that didn't match, printing it if it falls within the
+    /// after-context of a match or holding on to it as before-context
+    pub(crate) fn context(
+        &mut self,
+        line_number: usize,
+        byte_offset: usize,
+        line: &[u8],
+    ) -> io::Result<()> {
+        if !self.wants_context() {
+            return Ok(());
+        }
+        if self.after_remaining > 0 {
+            self.after_remaining -= 1;
+            return self.print_line(line_number, byte_offset, None, line, &[], None);
+        }
+        if self.config.before_context > 0 {
+            if self.before.len() == self.config.before_context {
+                self.before.pop_front();
+            }
+            self.before.push_back(ContextLine {
+                line_number,
+                byte_offset,
+                text: line.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Prints the name of the current source on its own, for -l and -L
+    pub(crate) fn path(&mut self) -> io::Result<()> {
+        match &self.source {
+            Some(source) => {
+                let path = self.paint(source, PATH_COLOR);
+                writeln!(self.out, "{path}")
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Prints that the current source is binary and has a selected line, in
+    /// place of the lines themselves
+    ///
+    /// With --json this is left to the "end" event instead.
+    pub(crate) fn binary_matches(&mut self) -> io::Result<()> {
+        self.binary_matched = true;
+        match &self.source {
+            Some(source) if !self.config.json => {
+                writeln!(self.out, "Binary file {source} matches")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Prints how many lines were selected from the current source, for -c
+    pub(crate) fn count(&mut self, selected: usize) -> io::Result<()> {
+        match (self.with_filename, &self.source) {
+            (true, Some(source)) => {
+                let path = self.paint(source, PATH_COLOR);
+                let separator = self.paint(":", SEPARATOR_COLOR);
+                writeln!(self.out, "{path}{separator}{selected}")
+            }
+            _ => writeln!(self.out, "{selected}"),
+        }
+    }
+
+    /// Prints a line prefixed with its source if asked for and with whichever
+    /// positions were asked for, e.g. "poem.txt:12:5:text"
+    ///
+    /// Matching lines are told apart from context lines by passing a column,
+    /// and use ":" after each prefix where context lines use "-".
+    ///
+    /// With -r, "replaced" is printed in place of the line, highlighting its
+    /// own ranges. As JSON, the line and its submatches are kept and each
+    /// submatch gets the text that replaces it, as ripgrep does.
+    fn print_line(
+        &mut self,
+        line_number: usize,
+        byte_offset: usize,
+        column: Option<usize>,
+        line: &[u8],
+        submatches: &[Range<usize>],
+        replaced: Option<(&[u8], &[Range<usize>])>,
+    ) -> io::Result<()> {
+        if self.config.json {
+            let kind = if column.is_some() { "match" } else { "context" };
+            let submatches: Vec<Value> = submatches
+                .iter()
+                .enumerate()
+                .filter(|(_, submatch)| submatch.end <= line.len())
+                .map(|(index, submatch)| {
+                    let mut submatch_json = json!({
+                        "match": data_json(&line[submatch.clone()]),
+                        "start": submatch.start,
+                        "end": submatch.end,
+                    });
+                    if let Some((replaced, replacements)) = replaced {
+                        submatch_json["replacement"] =
+                            data_json(&replaced[replacements[index].clone()]);
+                    }
+                    submatch_json
+                })
+                .collect();
+            return self.emit(
+                kind,
+                json!({
+                    "path": &self.path_json,
+                    "line": data_json(line),
+                    "line_number": line_number,
+                    "absolute_offset": byte_offset,
+                    "submatches": submatches,
+                }),
+            );
+        }
+
+        let contiguous = self
+            .last_printed
+            .is_some_and(|last| last + 1 == line_number);
+        if self.wants_context() && self.printed_any && !contiguous {
+            let separator = self.paint("--", SEPARATOR_COLOR);
+            writeln!(self.out, "{separator}")?;
+        }
+        self.last_printed = Some(line_number);
+        self.printed_any = true;
+
+        let mut prefix = String::new();
+        let separator = self.paint(if column.is_some() { ":" } else { "-" }, SEPARATOR_COLOR);
+
+        if let (true, Some(source)) = (self.with_filename, &self.source) {
+            prefix.push_str(&self.paint(source, PATH_COLOR));
+            prefix.push_str(&separator);
+        }
+        let positions = [
+            (self.config.line_number, Some(line_number)),
+            (self.config.column, column),
+            (self.config.byte_offset, Some(byte_offset)),
+        ];
+        for (_, position) in positions.iter().filter(|(enabled, _)| *enabled) {
+            if let Some(position) = position {
+                prefix.push_str(&self.paint(&position.to_string(), NUMBER_COLOR));
+                prefix.push_str(&separator);
+            }
+        }
+
+        // Lines are written out as they were read, even if they aren't UTF-8
+        let (line, submatches) = replaced.unwrap_or((line, submatches));
+        let mut output = prefix.into_bytes();
+        if self.config.color {
+            output.extend(highlight(line, submatches));
+        } else {
+            output.extend_from_slice(line);
+        }
+        output.push(b'\n');
+        self.out.write_all(&output)
+    }
+
+    /// Prints one line of JSON for an event, if --json is passed and -q isn't
+    fn emit(&mut self, kind: &str, data: Value) -> io::Result<()> {
+        if !self.config.json || self.config.quiet {
+            return Ok(());
+        }
+        writeln!(self.out, "{}", json!({ "type": kind, "data": data }))
+    }
+
+    /// Wraps text in an ANSI color when coloring is enabled
+    fn paint(&self, text: &str, color: &str) -> String {
+        if self.config.color {
+            format!("{color}{text}{RESET_COLOR}")
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Wraps every submatch of a line in the match color
+///
+/// Empty, overlapping or out of range submatches are left unhighlighted rather
+/// than producing broken output.
+///
+/// # Arguments
+///
+/// * "line" - the text of a matching line
+/// * "submatches" - byte ranges within the line to highlight, in ascending order
+fn highlight(line: &[u8], submatches: &[Range<usize>]) -> Vec<u8> {
+    let mut highlighted = Vec::with_capacity(line.len());
+    let mut written = 0;
+
+    for submatch in submatches {
+        if submatch.is_empty() || submatch.start < written || submatch.end > line.len() {
+            continue;
+        }
+        highlighted.extend_from_slice(&line[written..submatch.start]);
+        highlighted.extend_from_slice(MATCH_COLOR.as_bytes());
+        highlighted.extend_from_slice(&line[submatch.clone()]);
+        highlighted.extend_from_slice(RESET_COLOR.as_bytes());
+        written = submatch.end;
+    }
+    highlighted.extend_from_slice(&line[written..]);
+    highlighted
+}
+
+/// Bytes as JSON: {"text": ...} if they're UTF-8, or else {"bytes": ...}
+/// holding them in base64, so that any input survives the trip
+fn data_json(data: &[u8]) -> Value {
+    match str::from_utf8(data) {
+        Ok(text) => json!({ "text": text }),
+        Err(_) => json!({ "bytes": BASE64_STANDARD.encode(data) }),
+    }
+}
+
+/// A duration as JSON, both exactly and in seconds for people to read
+fn elapsed_json(elapsed: Duration) -> Value {
+    json!({
+        "secs": elapsed.as_secs(),
+        "nanos": elapsed.subsec_nanos(),
+        "human": format!("{:.6}s", elapsed.as_secs_f64()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands the lines of each source to a printer the way a search does,
+    /// selecting lines with "frog" in them, and returns what was printed
+    fn print(config: &Config, sources: &[&[&str]]) -> String {
+        let mut out = Vec::new();
+        let mut printer = Printer::new(config, sources.len() > 1, &mut out);
+        for (index, lines) in sources.iter().enumerate() {
+            printer.begin(Path::new(&format!("{index}.txt"))).unwrap();
+            let mut offset = 0;
+            for (line_number, line) in (1..).zip(lines.iter()) {
+                let submatches: Vec<_> = line
+                    .match_indices("frog")
+                    .map(|(start, found)| start..start + found.len())
+                    .collect();
+                if submatches.is_empty() == config.invert_match {
+                    let result = Match {
+                        line_number,
+                        line_range: offset..offset + line.len(),
+                        submatches,
+                        line: line.as_bytes(),
+                        source: None,
+                    };
+                    printer
+                        .matched(&result, None, |at| result.byte_offset() + at)
+                        .unwrap();
+                } else {
+                    printer
+                        .context(line_number, offset, line.as_bytes())
+                        .unwrap();
+                }
+                offset += line.len() + 1;
+            }
+        }
+        drop(printer);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn merges_overlapping_context() {
+        let config = Config {
+            line_number: true,
+            before_context: 1,
+            after_context: 1,
+            ..Config::default()
+        };
+        let lines = [
+            "one", "frog", "two", "frog", "three", "four", "five", "frog", "six",
+        ];
+
+        assert_eq!(
+            "1-one\n2:frog\n3-two\n4:frog\n5-three\n--\n7-five\n8:frog\n9-six\n",
+            print(&config, &[&lines])
+        );
+    }
+
+    #[test]
+    fn separates_context_across_sources() {
+        let config = Config {
+            after_context: 1,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            "0.txt:frog\n0.txt-one\n--\n1.txt:frog\n",
+            print(&config, &[&["frog", "one", "two"], &["frog"]])
+        );
+    }
+
+    #[test]
+    fn inverted_context() {
+        let config = Config {
+            line_number: true,
+            invert_match: true,
+            before_context: 1,
+            after_context: 1,
+            ..Config::default()
+        };
+        let lines = ["frog", "one", "frog", "frog", "frog", "two"];
+
+        assert_eq!(
+            "1-frog\n2:one\n3-frog\n--\n5-frog\n6:two\n",
+            print(&config, &[&lines])
+        );
+    }
+
+    #[test]
+    fn highlights_submatches() {
+        let line = b"How dreary to be somebody!";
+
+        assert_eq!(
+            b"How dreary to be \x1b[1;31msome\x1b[0mbody!".to_vec(),
+            highlight(line, &[17..21, 18..20, 25..25, 26..30])
+        );
+    }
+
+    #[test]
+    fn json_data_falls_back_to_base64() {
+        assert_eq!(json!({ "text": "Straße" }), data_json("Straße".as_bytes()));
+        assert_eq!(json!({ "bytes": "U3RyYd9l" }), data_json(b"Stra\xdfe"));
+    }
+}
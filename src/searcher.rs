@@ -0,0 +1,622 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::slice;
+
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use memchr::memmem;
+use memmap2::Mmap;
+
+use crate::matcher::Prefilter;
+use crate::Error;
+
+/// How much of a file is read at a time. The buffer only grows beyond this
+/// when a single line doesn't fit in it.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Files at least this big are memory-mapped, unless --no-mmap is passed.
+/// Below it, setting up the map costs about as much as copying the file;
+/// "cargo bench --bench mmap" shows mapping pulling ahead from here.
+const MMAP_THRESHOLD: u64 = 256 * 1024;
+
+/// The longest byte order mark, which is UTF-8's
+const BOM_LENGTH: usize = 3;
+
+/// A line handed out while searching
+/// number: 1-based line number within the input
+/// offset: byte offset of the start of the line within the input as it was read,
+/// even if the line has since been decoded
+/// bytes: the line itself, including its terminator, decoded to UTF-8 if the input
+/// was in another encoding
+/// binary: true if the input looks like a binary file, because there's a NUL
+/// character in its first chunk
+/// raw: the line as it was read, the same as bytes unless it was decoded
+/// encoding: the encoding the line was decoded from, or None if it wasn't
+pub(crate) struct Line<'a> {
+    pub(crate) number: usize,
+    pub(crate) offset: usize,
+    pub(crate) bytes: &'a [u8],
+    pub(crate) binary: bool,
+    pub(crate) raw: &'a [u8],
+    pub(crate) encoding: Option<&'static Encoding>,
+}
+
+impl Line<'_> {
+    /// Byte offset within the input of a byte offset within the line, which
+    /// only needs working out if the line was decoded
+    ///
+    /// The line is decoded again a byte at a time until "at" is reached, so
+    /// this is meant for the odd offset that gets printed, such as with -o -b.
+    pub(crate) fn input_offset(&self, at: usize) -> usize {
+        let Some(encoding) = self.encoding else {
+            return self.offset + at;
+        };
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut decoded = vec![0; decoder.max_utf8_buffer_length(1).unwrap_or(16)];
+        let mut written = 0;
+        for (read, byte) in self.raw.iter().enumerate() {
+            if written >= at {
+                return self.offset + read;
+            }
+            let (_, _, len, _) = decoder.decode_to_utf8(slice::from_ref(byte), &mut decoded, false);
+            written += len;
+        }
+        self.offset + self.raw.len()
+    }
+}
+
+/// Where lines are read from
+pub(crate) enum Input<'a> {
+    /// Read a chunk at a time, such as standard input or a small file
+    Stream(Box<dyn Read + 'a>),
+    /// Already in memory, such as a memory-mapped file
+    Bytes(&'a [u8]),
+}
+
+/// Decides whether to memory-map a file, given --mmap or --no-mmap if passed
+///
+/// Only regular files with a known size can be mapped, so pipes, devices and
+/// files like those in /proc are always streamed. Otherwise an explicit
+/// choice wins, and big files are mapped by default.
+pub(crate) fn should_mmap(file: &File, choice: Option<bool>) -> bool {
+    let Ok(metadata) = file.metadata() else {
+        return false;
+    };
+    if !metadata.is_file() || metadata.len() == 0 {
+        return false;
+    }
+    choice.unwrap_or(metadata.len() >= MMAP_THRESHOLD)
+}
+
+/// Memory-maps a whole file
+///
+/// # Safety
+///
+/// The map is only sound while nothing else truncates or modifies the file.
+/// As with other grep tools, that's accepted as the cost of speed; at worst a
+/// file changed mid-search gives odd results or a SIGBUS.
+pub(crate) fn mmap(file: &File, path: &Path) -> Result<Mmap, Error> {
+    unsafe { Mmap::map(file) }.map_err(Error::io(path))
+}
+
+/// Hands each line of the input to "on_line" as soon as it's been read
+///
+/// Lines are raw bytes, so input doesn't have to be valid UTF-8. "on_line" can
+/// return ControlFlow::Break to stop reading early. Streamed input is read a
+/// chunk at a time, so memory use is bounded by the buffer size or the longest
+/// line, whichever is bigger, and inputs of any size can be searched.
+///
+/// A UTF-8 byte order mark is skipped and the rest searched as it is. Input
+/// in any other encoding, from its byte order mark or as asked for, is
+/// decoded to UTF-8 a line at a time as it's streamed, even from memory.
+///
+/// Given a prefilter, only lines that contain a candidate are handed out, and
+/// the lines in between are skipped without being split, so sparse matches
+/// are found about as fast as the input can be scanned. Line numbers and
+/// offsets are still counted. Decoded input ignores the prefilter, since it
+/// applies to UTF-8.
+///
+/// # Arguments
+///
+/// * "input" - the input, such as an open file or standard input
+/// * "path" - where the input came from, for errors
+/// * "encoding" - the encoding of the input, or None for UTF-8
+/// * "prefilter" - finds the next line that's wanted, or None to want every line
+/// * "on_line" - called with every line in order
+pub(crate) fn for_each_line(
+    input: Input,
+    path: &Path,
+    encoding: Option<&'static Encoding>,
+    prefilter: Option<&Prefilter>,
+    on_line: impl FnMut(Line) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    match input {
+        Input::Stream(mut reader) => {
+            let mut peeked = Vec::with_capacity(BOM_LENGTH);
+            (&mut reader)
+                .take(BOM_LENGTH as u64)
+                .read_to_end(&mut peeked)
+                .map_err(Error::io(path))?;
+            for_each_line_buffered(
+                reader,
+                path,
+                &peeked,
+                BUFFER_SIZE,
+                encoding,
+                prefilter,
+                on_line,
+            )
+        }
+        Input::Bytes(bytes) => match decoder_for(bytes, encoding) {
+            (None, bom_length) => {
+                for_each_line_in_bytes(bytes, bom_length, prefilter, on_line);
+                Ok(())
+            }
+            // Decoded lines need somewhere to go, so they're streamed from memory
+            (Some(_), _) => {
+                let (peeked, rest) = bytes.split_at(bytes.len().min(BOM_LENGTH));
+                for_each_line_buffered(rest, path, peeked, BUFFER_SIZE, encoding, None, on_line)
+            }
+        },
+    }
+}
+
+/// Decides how input that starts with "start" has to be decoded, as the
+/// encoding to decode it from, or None if it's UTF-8 and can be searched as
+/// it is, and the length of its byte order mark
+///
+/// As in browsers, a byte order mark wins over whichever encoding was asked for.
+fn decoder_for(
+    start: &[u8],
+    encoding: Option<&'static Encoding>,
+) -> (Option<&'static Encoding>, usize) {
+    let (encoding, bom_length) = match Encoding::for_bom(start) {
+        Some((encoding, bom_length)) => (Some(encoding), bom_length),
+        None => (encoding, 0),
+    };
+    (encoding.filter(|&encoding| encoding != UTF_8), bom_length)
+}
+
+/// How "\n" is encoded in an encoding. Every encoding but UTF-16 leaves ASCII
+/// newlines as they are.
+fn encoded_newline(encoding: &'static Encoding) -> &'static [u8] {
+    if encoding == UTF_16LE {
+        b"\n\0"
+    } else if encoding == UTF_16BE {
+        b"\0\n"
+    } else {
+        b"\n"
+    }
+}
+
+/// Finds the end of the line at the start of "bytes", just after its newline,
+/// searching from "from", before which there's known to be no newline
+///
+/// Newlines have to start on a code unit boundary, so that e.g. the UTF-16LE
+/// bytes of U+0A0A aren't mistaken for one.
+fn find_line_end(bytes: &[u8], from: usize, newline: &[u8]) -> Option<usize> {
+    if let [newline] = newline {
+        return memchr::memchr(*newline, &bytes[from..]).map(|found| from + found + 1);
+    }
+    let mut from = from;
+    while let Some(found) = memmem::find(&bytes[from..], newline) {
+        let at = from + found;
+        if at.is_multiple_of(newline.len()) {
+            return Some(at + newline.len());
+        }
+        from = at + 1;
+    }
+    None
+}
+
+/// Finds the line holding the next candidate of a prefilter at or after
+/// "start", which has to be the start of a line
+///
+/// Returns the start of that line and how many lines were skipped to reach
+/// it, or None if there are no more candidates.
+fn skip_to_candidate(bytes: &[u8], start: usize, prefilter: &Prefilter) -> Option<(usize, usize)> {
+    let hit = start + prefilter.find(&bytes[start..])?;
+    let line_start =
+        memchr::memrchr(b'\n', &bytes[start..hit]).map_or(start, |newline| start + newline + 1);
+    let skipped = count_lines(&bytes[start..line_start]);
+    Some((line_start, skipped))
+}
+
+/// Splits UTF-8 input that is already in memory into lines, one at a time
+/// bytes: the whole input
+/// start: byte offset of the next line
+/// number: 1-based number of the line before it
+/// binary: whether the input looks like a binary file
+pub(crate) struct Lines<'a> {
+    bytes: &'a [u8],
+    start: usize,
+    number: usize,
+    binary: bool,
+}
+
+impl<'a> Lines<'a> {
+    /// Starts splitting "bytes" at "start", which is past any byte order mark
+    pub(crate) fn new(bytes: &'a [u8], start: usize) -> Lines<'a> {
+        Lines {
+            bytes,
+            start,
+            number: 0,
+            binary: is_binary(&bytes[start..bytes.len().min(start + BUFFER_SIZE)]),
+        }
+    }
+
+    /// Hands out the next line, or given a prefilter the next line that
+    /// contains a candidate, or None at the end of the input
+    pub(crate) fn next_line(&mut self, prefilter: Option<&Prefilter>) -> Option<Line<'a>> {
+        if self.start == self.bytes.len() {
+            return None;
+        }
+        if let Some(prefilter) = prefilter {
+            let Some((line_start, skipped)) = skip_to_candidate(self.bytes, self.start, prefilter)
+            else {
+                self.start = self.bytes.len();
+                return None;
+            };
+            self.start = line_start;
+            self.number += skipped;
+        }
+        let start = self.start;
+        self.start = memchr::memchr(b'\n', &self.bytes[start..])
+            .map_or(self.bytes.len(), |newline| start + newline + 1);
+        self.number += 1;
+        Some(Line {
+            number: self.number,
+            offset: start,
+            bytes: &self.bytes[start..self.start],
+            binary: self.binary,
+            raw: &self.bytes[start..self.start],
+            encoding: None,
+        })
+    }
+}
+
+/// Splits UTF-8 input that is already in memory into lines, from "start" on
+/// to skip a byte order mark
+fn for_each_line_in_bytes(
+    bytes: &[u8],
+    start: usize,
+    prefilter: Option<&Prefilter>,
+    mut on_line: impl FnMut(Line) -> ControlFlow<()>,
+) {
+    let mut lines = Lines::new(bytes, start);
+    while let Some(line) = lines.next_line(prefilter) {
+        if on_line(line).is_break() {
+            break;
+        }
+    }
+}
+
+/// Splits streamed input into lines, starting from a buffer of "capacity" bytes
+///
+/// "peeked" is the start of the input, already read from "reader" to look for
+/// a byte order mark. It's kept in the buffer rather than read again, so that
+/// the first read still fills the buffer and binary input is detected from
+/// the whole first chunk.
+///
+/// Input in another encoding is split on its own newlines and each line is
+/// decoded as it's handed out, so memory use stays bounded however it's
+/// encoded. The prefilter only applies to UTF-8, so it's ignored then.
+fn for_each_line_buffered(
+    mut reader: impl Read,
+    path: &Path,
+    peeked: &[u8],
+    capacity: usize,
+    encoding: Option<&'static Encoding>,
+    prefilter: Option<&Prefilter>,
+    mut on_line: impl FnMut(Line) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    let (encoding, bom_length) = decoder_for(peeked, encoding);
+    let mut decoder = encoding.map(Encoding::new_decoder_without_bom_handling);
+    let mut decoded = String::new();
+    let newline = encoding.map_or(b"\n".as_slice(), encoded_newline);
+    let prefilter = prefilter.filter(|_| encoding.is_none());
+
+    let mut buffer = vec![0; capacity.max(peeked.len()).max(1)];
+    buffer[..peeked.len()].copy_from_slice(peeked);
+    // buffer[start..end] holds input that hasn't been handed out yet, and
+    // buffer[start..scanned] is known not to contain a line terminator
+    let mut start = bom_length;
+    let mut scanned = bom_length;
+    let mut end = peeked.len();
+    let mut number = 0;
+    let mut offset = bom_length;
+    let mut binary = None;
+
+    loop {
+        if end == buffer.len() {
+            if start > 0 {
+                buffer.copy_within(start..end, 0);
+                scanned -= start;
+                end -= start;
+                start = 0;
+            } else {
+                buffer.resize(buffer.len() * 2, 0);
+            }
+        }
+
+        let read = match reader.read(&mut buffer[end..]) {
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(Error::io(path)(error)),
+        };
+        end += read;
+        let binary = *binary.get_or_insert_with(|| match encoding {
+            Some(encoding) => encoding
+                .decode_without_bom_handling(&buffer[start..end])
+                .0
+                .contains('\0'),
+            None => is_binary(&buffer[start..end]),
+        });
+
+        loop {
+            if let Some(prefilter) = prefilter {
+                // Without a candidate, every finished line can be skipped, but
+                // one may still turn up in the unfinished last line
+                let (line_start, skipped) =
+                    match skip_to_candidate(&buffer[..end], start, prefilter) {
+                        Some(candidate) => candidate,
+                        None if read == 0 => return Ok(()),
+                        None => {
+                            let line_start = memchr::memrchr(b'\n', &buffer[start..end])
+                                .map_or(start, |newline| start + newline + 1);
+                            (line_start, count_lines(&buffer[start..line_start]))
+                        }
+                    };
+                number += skipped;
+                offset += line_start - start;
+                start = line_start;
+                scanned = scanned.max(start);
+            }
+
+            let Some(line_end) = find_line_end(&buffer[start..end], scanned - start, newline)
+            else {
+                break;
+            };
+            let line_end = start + line_end;
+            number += 1;
+            let line = Line {
+                number,
+                offset,
+                bytes: decode(&mut decoder, &buffer[start..line_end], &mut decoded, false),
+                binary,
+                raw: &buffer[start..line_end],
+                encoding,
+            };
+            if on_line(line).is_break() {
+                return Ok(());
+            }
+            offset += line_end - start;
+            start = line_end;
+            scanned = line_end;
+        }
+        // Part of a newline may have been read, but only whole code units
+        // are left behind
+        scanned = end - (end - start) % newline.len();
+
+        if read == 0 {
+            // The last line may not have a terminator. Whether the caller
+            // wants to stop makes no difference at the end of the input.
+            if start < end {
+                let _ = on_line(Line {
+                    number: number + 1,
+                    offset,
+                    bytes: decode(&mut decoder, &buffer[start..end], &mut decoded, true),
+                    binary,
+                    raw: &buffer[start..end],
+                    encoding,
+                });
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Decodes a line to UTF-8 if there's a decoder, or else hands it back as it is
+///
+/// The decoder carries over from line to line, so that encodings with state
+/// are decoded correctly, and "last" says whether this is the end of the
+/// input. Malformed sequences are decoded as U+FFFD.
+fn decode<'a>(
+    decoder: &mut Option<Decoder>,
+    line: &'a [u8],
+    decoded: &'a mut String,
+    last: bool,
+) -> &'a [u8] {
+    let Some(decoder) = decoder else {
+        return line;
+    };
+    decoded.clear();
+    if let Some(needed) = decoder.max_utf8_buffer_length(line.len()) {
+        decoded.reserve(needed);
+    }
+    let _ = decoder.decode_to_string(line, decoded, last);
+    decoded.as_bytes()
+}
+
+/// Counts the line terminators in some bytes
+fn count_lines(bytes: &[u8]) -> usize {
+    memchr::memchr_iter(b'\n', bytes).count()
+}
+
+/// Guesses whether input is binary the way grep does, by looking for a NUL
+/// byte, which UTF-8 text doesn't contain
+fn is_binary(bytes: &[u8]) -> bool {
+    memchr::memchr(0, bytes).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect_lines(
+        input: Input,
+        capacity: usize,
+        prefilter: Option<&Prefilter>,
+    ) -> Vec<(usize, usize, Vec<u8>, bool)> {
+        let mut lines = Vec::new();
+        let on_line = |line: Line| {
+            lines.push((line.number, line.offset, line.bytes.to_vec(), line.binary));
+            ControlFlow::Continue(())
+        };
+        match input {
+            Input::Stream(reader) => for_each_line_buffered(
+                reader,
+                Path::new("poem.txt"),
+                &[],
+                capacity,
+                None,
+                prefilter,
+                on_line,
+            )
+            .unwrap(),
+            Input::Bytes(bytes) => for_each_line_in_bytes(bytes, 0, prefilter, on_line),
+        }
+        lines
+    }
+
+    #[test]
+    fn streamed_and_in_memory_lines_agree() {
+        let input = b"I'm nobody! Who are you?\nAre you nobody, too?\r\n\nTh\xffen";
+        let expected = vec![
+            (1, 0, b"I'm nobody! Who are you?\n".to_vec(), false),
+            (2, 25, b"Are you nobody, too?\r\n".to_vec(), false),
+            (3, 47, b"\n".to_vec(), false),
+            (4, 48, b"Th\xffen".to_vec(), false),
+        ];
+
+        assert_eq!(
+            expected,
+            collect_lines(Input::Stream(Box::new(&input[..])), 4, None)
+        );
+        assert_eq!(expected, collect_lines(Input::Bytes(input), 0, None));
+    }
+
+    #[test]
+    fn prefilter_skips_lines_without_a_candidate() {
+        let input = b"I'm nobody! Who are you?\nAre you nobody, too?\nThen there's a pair of us\n";
+        let finder = memmem::Finder::new("you");
+        let prefilter = Prefilter::Literal(&finder);
+        let expected = vec![
+            (1, 0, b"I'm nobody! Who are you?\n".to_vec(), false),
+            (2, 25, b"Are you nobody, too?\n".to_vec(), false),
+        ];
+
+        assert_eq!(
+            expected,
+            collect_lines(Input::Stream(Box::new(&input[..])), 4, Some(&prefilter))
+        );
+        assert_eq!(
+            expected,
+            collect_lines(Input::Bytes(input), 0, Some(&prefilter))
+        );
+        assert!(collect_lines(
+            Input::Bytes(input),
+            0,
+            Some(&Prefilter::Literal(&memmem::Finder::new("frog")))
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn decodes_with_offsets_into_the_original() {
+        let utf16: Vec<u8> = b"\xff\xfe"
+            .iter()
+            .copied()
+            .chain(
+                "caf\u{e9}\r\n\u{a0a}\n"
+                    .encode_utf16()
+                    .flat_map(u16::to_le_bytes),
+            )
+            .collect();
+        let mut lines = Vec::new();
+        for_each_line(
+            Input::Bytes(&utf16),
+            Path::new("poem.txt"),
+            None,
+            None,
+            |line| {
+                lines.push((line.number, line.offset, line.bytes.to_vec()));
+                ControlFlow::Continue(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                (1, 2, "caf\u{e9}\r\n".as_bytes().to_vec()),
+                (2, 14, "\u{a0a}\n".as_bytes().to_vec()),
+            ],
+            lines
+        );
+
+        // Streamed input is decoded a line at a time, including when a
+        // newline is split between reads
+        for capacity in 1..=5 {
+            let mut streamed = Vec::new();
+            for_each_line_buffered(
+                &utf16[BOM_LENGTH..],
+                Path::new("poem.txt"),
+                &utf16[..BOM_LENGTH],
+                capacity,
+                None,
+                None,
+                |line| {
+                    streamed.push((line.number, line.offset, line.bytes.to_vec()));
+                    ControlFlow::Continue(())
+                },
+            )
+            .unwrap();
+            assert_eq!(lines, streamed, "capacity {capacity}");
+        }
+    }
+
+    #[test]
+    fn skips_a_utf8_byte_order_mark() {
+        let input = b"\xef\xbb\xbfcaf\xe9\nfrog\n";
+        let expected = vec![
+            (1, 3, b"caf\xe9\n".to_vec(), false),
+            (2, 8, b"frog\n".to_vec(), false),
+        ];
+
+        for input in [Input::Stream(Box::new(&input[..])), Input::Bytes(input)] {
+            let mut lines = Vec::new();
+            for_each_line(input, Path::new("poem.txt"), None, None, |line| {
+                lines.push((line.number, line.offset, line.bytes.to_vec(), line.binary));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+            assert_eq!(expected, lines);
+        }
+    }
+
+    #[test]
+    fn detects_binary() {
+        let lines = collect_lines(Input::Bytes(b"ELF\0\x01\nfrog\n"), 0, None);
+
+        assert!(lines.iter().all(|(_, _, _, binary)| *binary));
+
+        // Streams are peeked at for a byte order mark first, which mustn't
+        // hide a NUL byte further into the first chunk
+        let input = b"hello\0world\nfrog here\n";
+        let mut binary = Vec::new();
+        for_each_line(
+            Input::Stream(Box::new(&input[..])),
+            Path::new("poem.txt"),
+            None,
+            None,
+            |line| {
+                binary.push(line.binary);
+                ControlFlow::Continue(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(vec![true, true], binary);
+    }
+}